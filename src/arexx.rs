@@ -1,31 +1,58 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::{
+    sync::atomic::{AtomicBool, Ordering},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use std::cell::Cell;
-use anyhow::{bail, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
-use crate::config::{ConfigFile, SensorConfig};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use crate::config::{ConfigFile, SensorConfig, DEFAULT_SENSOR_KIND};
+use crate::error::{DecodeError, DeviceError, Result};
+use crate::profile::{DeviceProfile, InitCommand, TupleLayout, PACKET_SIZE};
 use crate::usb::{self, UsbDevice, UsbInner};
 
 const INTERNAL_TEMPERATURE_SCALE: f32 = 0.0078;
 
-#[derive(Debug, Serialize, Deserialize)]
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const NOT_AVAILABLE_DELAY: Duration = Duration::from_secs(5);
+const STREAM_BUFFER_SIZE: usize = 64;
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+const EMPTY_SENSOR_ID: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TemperatureReading {
     pub timestamp: DateTime<FixedOffset>,
     pub sensor: u16,
+    pub name: String,
+    pub kind: String,
+    pub station: String,
+    /// Tags of the sensor, serialized as fields of the reading.
+    #[serde(flatten)]
+    pub tags: BTreeMap<String, String>,
     pub value: f32,
+    /// Value as sent by the sensor, `value` is `raw_value * scaling`. Missing
+    /// in readings spooled by an older version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_value: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scaling: Option<f32>,
 }
 
 impl Display for TemperatureReading {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Temperature[time: {}, sensor: {}, temp: {}]",
-            self.timestamp, self.sensor, self.value
+            "Temperature[time: {}, sensor: {} ({}), temp: {}]",
+            self.timestamp, self.sensor, self.name, self.value
         )
     }
 }
@@ -34,21 +61,135 @@ impl Display for TemperatureReading {
 pub struct Arexx {
     start_time: Cell<Option<DateTime<FixedOffset>>>,
     connect_initialized: usize,
+    pending: VecDeque<TemperatureReading>,
+    pub profile: DeviceProfile,
+    pub station: String,
     pub sensor_config_lookup: HashMap<u16,SensorConfig>,
     pub usb: Arc<Mutex<UsbDevice>>,
 }
 
-fn create_arexx_date_bytes(date_time: DateTime<FixedOffset>) -> Result<[u8; 4]> {
+impl Drop for Arexx {
+    fn drop(&mut self) {
+        self.usb.lock().unwrap().stop();
+    }
+}
+
+fn create_arexx_date_bytes(date_time: DateTime<FixedOffset>) -> [u8; 4] {
     let ref_date: DateTime<Utc> = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
     let arexx_init_seconds = date_time.signed_duration_since(ref_date).num_seconds() as u32;
     tracing::trace!("initialize arexx with {} seconds since \"2000-01-01 00:00:00\"", arexx_init_seconds);
-    Ok(arexx_init_seconds.to_le_bytes())
+    arexx_init_seconds.to_le_bytes()
 }
 
-fn parse_arexx_date_bytes(bytes: [u8; 4]) -> Result<DateTime<FixedOffset>> {
+/// Every 32 bit value is a valid timestamp, up to 2136.
+fn parse_arexx_date_bytes(bytes: [u8; 4]) -> DateTime<FixedOffset> {
     let ref_date: DateTime<Utc> = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
     let secs = u32::from_le_bytes(bytes);
-    Ok((ref_date + Duration::from_secs(secs.into())).into())
+    (ref_date + Duration::from_secs(secs.into())).into()
+}
+
+/// Async stream of the temperature readings of a connected base station.
+///
+/// Created with [`Arexx::stream`].
+pub struct ReadingStream {
+    inner: TryReadingStream,
+}
+
+impl Stream for ReadingStream {
+    type Item = TemperatureReading;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(reading))) => return Poll::Ready(Some(reading)),
+                Poll::Ready(Some(Err(error))) if error.is_retryable() => {
+                    tracing::error!("error reading record: {}", error);
+                }
+                Poll::Ready(Some(Err(error))) => {
+                    tracing::error!("fatal error reading record: {}", error);
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Async stream of the temperature readings and read errors of a connected
+/// base station.
+///
+/// Created with [`Arexx::try_stream`].
+pub struct TryReadingStream {
+    inner: ReceiverStream<Result<TemperatureReading>>,
+    cancel: Arc<AtomicBool>,
+    _reader: JoinHandle<()>,
+}
+
+impl Drop for TryReadingStream {
+    fn drop(&mut self) {
+        // stops the reader task
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Stream for TryReadingStream {
+    type Item = Result<TemperatureReading>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+/// Sensor data tuple of a reply packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawTuple {
+    pub sensor_id: u16,
+    pub raw_value: u16,
+    pub timestamp: DateTime<FixedOffset>,
+    pub signal_quality: Option<u8>,
+}
+
+fn decode_tuple(tuple: &[u8]) -> RawTuple {
+    RawTuple {
+        sensor_id: u16::from_le_bytes([tuple[1], tuple[2]]),
+        raw_value: u16::from_be_bytes([tuple[3], tuple[4]]),
+        timestamp: parse_arexx_date_bytes([tuple[5], tuple[6], tuple[7], tuple[8]]),
+        signal_quality: tuple.get(9).copied(),
+    }
+}
+
+/// Decodes the sensor data tuples of a reply packet. Empty tuples (sensor ID
+/// 0xFFFF) are skipped.
+pub fn decode_tuples(packet: &[u8], layout: TupleLayout) -> Result<Vec<RawTuple>, DecodeError> {
+    let mut tuples = Vec::new();
+    match layout {
+        TupleLayout::Single => {
+            if packet.len() < 10 {
+                return Err(DecodeError::ShortPacket(packet.len()));
+            }
+            tuples.push(decode_tuple(&packet[1..10]));
+        }
+        TupleLayout::Sequence => {
+            let mut offset = 1;
+            while offset < packet.len() {
+                let tuple_len = packet[offset] as usize;
+                if tuple_len == 0 {
+                    break;
+                }
+                if tuple_len < 9 {
+                    return Err(DecodeError::InvalidTupleLength(tuple_len));
+                }
+                if offset + tuple_len > packet.len() {
+                    return Err(DecodeError::ShortPacket(packet.len()));
+                }
+                tuples.push(decode_tuple(&packet[offset..offset + tuple_len]));
+                offset += tuple_len;
+            }
+        }
+    }
+    tuples.retain(|tuple| tuple.sensor_id != EMPTY_SENSOR_ID);
+    Ok(tuples)
 }
 
 pub enum ArexxResult {
@@ -57,6 +198,18 @@ pub enum ArexxResult {
     NotAvailable
 }
 
+/// Sleeps for `duration`, returns early once `cancel` is set.
+fn sleep_unless_cancelled(cancel: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !cancel.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        std::thread::sleep((deadline - now).min(CANCEL_CHECK_INTERVAL));
+    }
+}
+
 fn parse_start_time(start_time: Option<String>) -> Option<DateTime<FixedOffset>> {
     if let Some(ts) = start_time {
         let local: DateTime<Local> = Local::now();
@@ -84,9 +237,9 @@ fn parse_start_time(start_time: Option<String>) -> Option<DateTime<FixedOffset>>
 
 impl Arexx {
     pub fn new(config: ConfigFile, start_time: Option<String>) -> Result<Arexx> {
-        let vid = config.vid;
-        let pid = config.pid;
-        let usb = usb::UsbDevice::new(vid, pid)?;
+        let profile = config.device_profile()?;
+        let station = config.station()?;
+        let usb = usb::UsbDevice::new(&profile)?;
 
         let mut sensor_config_lookup = HashMap::new();
         let fallback_temperature_scaling = config.temperature_scaling.unwrap_or(INTERNAL_TEMPERATURE_SCALE);
@@ -99,36 +252,137 @@ impl Arexx {
 
         Ok(Arexx {
             usb,
+            profile,
+            station,
             sensor_config_lookup,
             connect_initialized: 0,
+            pending: VecDeque::new(),
             start_time: Cell::new(parse_start_time(start_time))
         })
     }
 
-    fn init_arexx(&self, usb_inner: &UsbInner) -> anyhow::Result<()> {
-        let mut buf: [u8; 64] = [0; 64];
-        let timeout = Duration::from_secs(30);
+    fn init_arexx(&self, usb_inner: &UsbInner) -> Result<(), DeviceError> {
+        for command in &self.profile.init_sequence {
+            let mut buf: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+            match command {
+                InitCommand::SetClock => {
+                    let arexx_start_time = self.start_time.get().unwrap_or(Local::now().fixed_offset());
+                    tracing::info!("init arexx with start time {}", arexx_start_time);
+                    // passed start_time should only be applied once on the first init.
+                    // reset therefor start_time to None so that the current time is used afterwards.
+                    self.start_time.set(None);
+
+                    buf[0] = 0x04;
+                    buf[1..5].copy_from_slice(&create_arexx_date_bytes(arexx_start_time));
+                }
+            }
 
-        let arexx_start_time = self.start_time.get().unwrap_or(Local::now().fixed_offset());
-        tracing::info!("init arexx with start time {}", arexx_start_time);
-        // passed start_time should only be applied once on the first init.
-        // reset therefor start_time to None so that the current time is used afterwards.
-        self.start_time.set(None);
+            let write_addr = usb_inner.endpoints.write_addr;
+            match usb_inner.handle.borrow().write_bulk(write_addr, &buf, TRANSFER_TIMEOUT) {
+                Ok(len) => {
+                    tracing::debug!("arexx init {:?} written {} bytes", command, len);
+                }
+                Err(error) => return Err(error.into())
+            }
+        }
+        Ok(())
+    }
 
-        buf[0] = 0x04;
-        buf[1..5].copy_from_slice(&create_arexx_date_bytes(arexx_start_time)?);
+    /// Turns the device into an async stream of temperature readings.
+    ///
+    /// Retryable errors are logged and polling continues, the stream ends on
+    /// the first fatal error. Dropping the stream stops the polling.
+    pub fn stream(self) -> ReadingStream {
+        ReadingStream {
+            inner: self.try_stream(),
+        }
+    }
 
-        let write_addr = usb_inner.endpoints.write_addr;
-        match usb_inner.handle.borrow().write_bulk(write_addr, &buf, timeout) {
-            Ok(len) => {
-                tracing::debug!("arexx init written {} bytes", len);
-                anyhow::Ok(())
+    /// Turns the device into an async stream of temperature readings and
+    /// read errors.
+    ///
+    /// The blocking USB polling runs on a dedicated blocking task. Retryable
+    /// errors are passed on and polling continues, a fatal error is passed on
+    /// as last item of the stream. Dropping the stream stops the blocking
+    /// task after the current USB transfer.
+    pub fn try_stream(mut self) -> TryReadingStream {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let reader_cancel = cancel.clone();
+        let reader = tokio::task::spawn_blocking(move || {
+            while !reader_cancel.load(Ordering::Relaxed) && !tx.is_closed() {
+                let item = match self.read_record() {
+                    Ok(ArexxResult::Temperature(reading)) => {
+                        tracing::debug!("read record: {:?}", &reading);
+                        Some(Ok(reading))
+                    }
+                    Ok(ArexxResult::NotAvailable) => {
+                        tracing::info!("Arexx device not available. Sleep {} secs", NOT_AVAILABLE_DELAY.as_secs());
+                        sleep_unless_cancelled(&reader_cancel, NOT_AVAILABLE_DELAY);
+                        None
+                    }
+                    Ok(ArexxResult::Other) => {
+                        tracing::debug!("Ignore other data");
+                        None
+                    }
+                    Err(error) => Some(Err(error)),
+                };
+                if let Some(item) = item {
+                    let fatal = matches!(&item, Err(error) if error.is_fatal());
+                    if tx.blocking_send(item).is_err() {
+                        break;
+                    }
+                    if fatal {
+                        break;
+                    }
+                }
+                sleep_unless_cancelled(&reader_cancel, POLL_INTERVAL);
             }
-            Err(error) => bail!(error)
+            tracing::debug!("reading stream closed");
+        });
+
+        TryReadingStream {
+            inner: ReceiverStream::new(rx),
+            cancel,
+            _reader: reader,
         }
     }
 
+    /// Converts the tuples of configured sensors to temperature readings.
+    fn scale_tuples(&self, tuples: Vec<RawTuple>) -> Vec<TemperatureReading> {
+        tuples
+            .into_iter()
+            .filter_map(|tuple| match self.sensor_config_lookup.get(&tuple.sensor_id) {
+                Some(sensor_config) => {
+                    let scaling = sensor_config.temperature_scaling.get().unwrap();
+                    let scaled_value = tuple.raw_value as f32 * scaling;
+                    tracing::trace!("sensor {}, value={}, scaled_value={}", &tuple.sensor_id, tuple.raw_value, scaled_value);
+                    Some(TemperatureReading {
+                        timestamp: tuple.timestamp,
+                        sensor: tuple.sensor_id,
+                        name: sensor_config.name.clone(),
+                        kind: sensor_config.kind.clone().unwrap_or(DEFAULT_SENSOR_KIND.to_string()),
+                        station: self.station.clone(),
+                        tags: sensor_config.tags.clone(),
+                        value: scaled_value,
+                        raw_value: Some(tuple.raw_value),
+                        scaling: Some(scaling),
+                    })
+                }
+                None => {
+                    tracing::trace!("temperature read from unknown sensor ID {}", &tuple.sensor_id);
+                    None
+                }
+            })
+            .collect()
+    }
+
     pub fn read_record(&mut self) -> Result<ArexxResult> {
+        // readings of a previous multi-tuple packet are returned first
+        if let Some(reading) = self.pending.pop_front() {
+            return Ok(ArexxResult::Temperature(reading));
+        }
+
         let connect_count = self.usb.lock().unwrap().connect_count;
         if let Some(ref usb_inner) = self.usb.lock().unwrap().inner {
             if self.connect_initialized != connect_count {
@@ -139,65 +393,110 @@ impl Arexx {
             let handle = usb_inner.handle.borrow();
             let endpoints = usb_inner.endpoints;
 
-            let mut buf: [u8; 64] = [0; 64];
-            let timeout = Duration::from_secs(30);
+            let mut buf: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
 
             // trigger arexx to send data
             buf[0] = 0x03;
-            match handle.write_bulk(endpoints.write_addr, &buf, timeout) {
+            match handle.write_bulk(endpoints.write_addr, &buf, TRANSFER_TIMEOUT) {
                 Ok(len) => {
                     tracing::trace!("successfully sent trigger to arexx ({})", len)
                 }
                 Err(err) => {
                     tracing::error!("arexx trigger: Error ({:?})", err);
-                    bail!("failed to trigger arexx: {}", err);
+                    return Err(err.into());
                 }
             }
 
             // read data
-            match handle.read_bulk(endpoints.read_addr, &mut buf, timeout) {
-                Ok(_len) => {
-                    let sensor_id_bytes = buf[2..4].try_into()?;
-                    let sensor_id = u16::from_le_bytes(sensor_id_bytes);
-
-                    let value_bytes = buf[4..6].try_into()?;
-                    let value = u16::from_be_bytes(value_bytes);
-
-                    let ts_bytes = buf[6..10].try_into()?;
-                    let timestamp = parse_arexx_date_bytes(ts_bytes);
-
-                    tracing::trace!("read_bulk: {:?} {}", timestamp, sensor_id);
-                    if let Ok(ts) = timestamp {
-                        if sensor_id != 0xFFFF {
-                            match self.sensor_config_lookup.get(&sensor_id) {
-                                Some(sensor_config) => {
-                                    let scaled_value = value as f32 * sensor_config.temperature_scaling.get().unwrap();
-                                    tracing::trace!("sensor {}, value={}, scaled_value={}", &sensor_id, value, scaled_value);
-                                    Ok(ArexxResult::Temperature(TemperatureReading {
-                                        timestamp: ts,
-                                        sensor: sensor_id,
-                                        value: scaled_value,
-                                    }))
-                                },
-                                None => {
-                                    tracing::trace!("temperature read from unknown sensor ID {}", &sensor_id);
-                                    Ok(ArexxResult::Other)
-                                }
-                            }
-                        } else {
-                            Ok(ArexxResult::Other)
-                        }
-                    } else {
-                        bail!("error parsing timestamp: {:?}", ts_bytes)
+            match handle.read_bulk(endpoints.read_addr, &mut buf, TRANSFER_TIMEOUT) {
+                Ok(len) => {
+                    let tuples = decode_tuples(&buf[..len], self.profile.tuple_layout)?;
+                    tracing::trace!("read_bulk: {:?}", tuples);
+                    self.pending.extend(self.scale_tuples(tuples));
+                    match self.pending.pop_front() {
+                        Some(reading) => Ok(ArexxResult::Temperature(reading)),
+                        None => Ok(ArexxResult::Other),
                     }
                 }
                 Err(err) => {
                     tracing::error!("failed to read from arexx endpoint: {}", err);
-                    bail!(err.to_string());
+                    Err(err.into())
                 }
             }
         } else {
             Ok(ArexxResult::NotAvailable)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tuple with its length byte, 9 bytes or 10 with signal quality.
+    fn tuple(sensor_id: u16, raw_value: u16, secs: u32, signal_quality: Option<u8>) -> Vec<u8> {
+        let mut tuple = vec![if signal_quality.is_some() { 10 } else { 9 }];
+        tuple.extend(sensor_id.to_le_bytes());
+        tuple.extend(raw_value.to_be_bytes());
+        tuple.extend(secs.to_le_bytes());
+        tuple.extend(signal_quality);
+        tuple
+    }
+
+    fn packet(tuples: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = vec![0];
+        packet.extend(tuples.concat());
+        packet.resize(PACKET_SIZE, 0);
+        packet
+    }
+
+    fn raw(sensor_id: u16, raw_value: u16, secs: u32, signal_quality: Option<u8>) -> RawTuple {
+        RawTuple {
+            sensor_id,
+            raw_value,
+            timestamp: parse_arexx_date_bytes(secs.to_le_bytes()),
+            signal_quality,
+        }
+    }
+
+    #[test]
+    fn parses_date_since_2000() {
+        let timestamp = parse_arexx_date_bytes(86_400u32.to_le_bytes());
+        assert_eq!(timestamp.to_rfc3339(), "2000-01-02T00:00:00+00:00");
+    }
+
+    #[test]
+    fn decodes_first_tuple_of_single_layout() {
+        let packet = packet(&[tuple(1111, 0x0A3C, 1000, None), tuple(2222, 0x0B00, 2000, None)]);
+        assert_eq!(decode_tuples(&packet, TupleLayout::Single).unwrap(), vec![raw(1111, 0x0A3C, 1000, None)]);
+    }
+
+    #[test]
+    fn decodes_sequence_until_empty_tuple() {
+        assert!(decode_tuples(&packet(&[]), TupleLayout::Sequence).unwrap().is_empty());
+        let packet = packet(&[
+            tuple(1111, 0x0A3C, 1000, Some(80)),
+            tuple(EMPTY_SENSOR_ID, 0, 0, None),
+            tuple(2222, 0x0B00, 2000, None),
+        ]);
+        assert_eq!(
+            decode_tuples(&packet, TupleLayout::Sequence).unwrap(),
+            vec![raw(1111, 0x0A3C, 1000, Some(80)), raw(2222, 0x0B00, 2000, None)]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        assert!(matches!(decode_tuples(&[0; 9], TupleLayout::Single), Err(DecodeError::ShortPacket(9))));
+
+        let mut invalid_length = packet(&[tuple(1111, 0x0A3C, 1000, None)]);
+        invalid_length[1] = 5;
+        assert!(matches!(
+            decode_tuples(&invalid_length, TupleLayout::Sequence),
+            Err(DecodeError::InvalidTupleLength(5))
+        ));
+
+        let truncated = &packet(&[tuple(1111, 0x0A3C, 1000, None)])[..8];
+        assert!(matches!(decode_tuples(truncated, TupleLayout::Sequence), Err(DecodeError::ShortPacket(8))));
+    }
+}
@@ -1,35 +1,127 @@
-use std::{cell::Cell, path::PathBuf};
+use std::{cell::Cell, collections::BTreeMap, path::PathBuf, time::Duration};
 
-use anyhow::{Context, Ok, Result};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::error::ConfigError;
+use crate::profile::{DeviceConfig, DeviceProfile};
+use crate::sink::{slugify, SinkFilter, SinkRegistry};
+use crate::template::Template;
+
+/// Kind of the sensors when not configured otherwise.
+pub const DEFAULT_SENSOR_KIND: &str = "temperature";
+
+/// Maximum size of the spool of a sink when not configured otherwise.
+const DEFAULT_SPOOL_MAX_SIZE_MB: u64 = 100;
+
+/// Sink settings which are not printed.
+const SECRET_SETTINGS: [&str; 1] = ["password"];
+
+/// Tag names which collide with the fields of a reading in the sink output.
+const RESERVED_TAGS: [&str; 8] = ["timestamp", "time", "sensor", "sensor_id", "name", "kind", "station", "value"];
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct ConfigFile {
-    pub vid: u16,
-    pub pid: u16,
+    /// Legacy USB IDs, superseded by the `[device]` section.
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+
+    pub device: Option<DeviceConfig>,
 
     #[serde(rename = "temperature-scaling")]
     pub temperature_scaling: Option<f32>,
 
     pub log: Option<LogConfig>,
 
-    pub sink: Vec<SinkTypeConfig>,
+    pub spool: Option<SpoolConfig>,
+
+    pub sink: Vec<SinkConfig>,
 
     pub sensors: Vec<SensorConfig>,
 }
 
-impl Default for ConfigFile {
-    fn default() -> Self {
-        Self { vid: 0x0451, pid: 0x3211, temperature_scaling: None, log: Default::default(), sink: Default::default(), sensors: Default::default(), }
+impl ConfigFile {
+    /// Resolves the device profile of the `[device]` section. The legacy
+    /// top-level `vid`/`pid` settings override the IDs of the profile.
+    pub fn device_profile(&self) -> Result<DeviceProfile, ConfigError> {
+        let mut device_config = self.device.clone().unwrap_or_default();
+        device_config.vid = device_config.vid.or(self.vid);
+        device_config.pid = device_config.pid.or(self.pid);
+        device_config.resolve()
+    }
+
+    /// Name of the base station: the configured `station` or the profile name.
+    pub fn station(&self) -> Result<String, ConfigError> {
+        match self.device.as_ref().and_then(|device| device.station.clone()) {
+            Some(station) => Ok(station),
+            None => Ok(self.device_profile()?.name),
+        }
+    }
+
+    /// Validates the settings which depend on each other, e.g. that the
+    /// sensor tags used in sink templates are defined.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.validate_with(&SinkRegistry::default())
+    }
+
+    /// Validates the configuration with the sink types of `registry`. The
+    /// settings of disabled sinks are not checked.
+    pub fn validate_with(&self, registry: &SinkRegistry) -> Result<(), ConfigError> {
+        self.device_profile()?;
+        let mut sensor_names: Vec<String> = Vec::new();
+        for sensor in &self.sensors {
+            // the name is used as topic level and in names of the sinks
+            let slug = slugify(&sensor.name);
+            if slug.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "sensor {}: name `{}` needs at least one letter or digit", sensor.id, sensor.name
+                )));
+            }
+            if sensor_names.contains(&slug) {
+                return Err(ConfigError::Invalid(format!(
+                    "sensor {}: name `{}` is not unique (`{}`)", sensor.id, sensor.name, slug
+                )));
+            }
+            sensor_names.push(slug);
+            if let Some(tag) = sensor.tags.keys().find(|tag| RESERVED_TAGS.contains(&tag.as_str())) {
+                return Err(ConfigError::Invalid(format!(
+                    "sensor {} ({}): tag `{}` is reserved", sensor.id, sensor.name, tag
+                )));
+            }
+        }
+        let mut spool_directories: Vec<String> = Vec::new();
+        for (index, sink_config) in self.sink.iter().enumerate() {
+            if sink_config.enabled {
+                registry.validate(sink_config, self)?;
+            }
+            if let Some(name) = &sink_config.options.name {
+                if slugify(name).is_empty() {
+                    return Err(ConfigError::Invalid(format!("sink name `{}` is invalid", name)));
+                }
+            }
+            // unnamed sinks use their position, which a name may also produce
+            let spool_directory = sink_config.spool_directory(index + 1);
+            if spool_directories.contains(&spool_directory) {
+                return Err(ConfigError::Invalid(format!(
+                    "sink #{} `{}` is not unique, another sink uses the spool directory `{}`",
+                    index + 1,
+                    sink_config.label(),
+                    spool_directory
+                )));
+            }
+            spool_directories.push(spool_directory);
+        }
+        Ok(())
     }
-}
 
-impl ConfigFile {
     pub fn print(self) {
         println!("\nConfiguration");
-        println!("  USB Port: vid = 0x{:04x}, pid = 0x{:04x}", self.vid, self.pid);
-        if self.temperature_scaling.is_some() {
-            println!("  Global temperature scale = {}", self.temperature_scaling.unwrap());
+        match self.device_profile() {
+            Ok(profile) => println!("  Device: {}", profile),
+            Err(error) => println!("  Device: {}", error),
+        }
+        if let Some(temperature_scaling) = self.temperature_scaling {
+            println!("  Global temperature scale = {}", temperature_scaling);
         }
         if let Some(log_config) = self.log {
             if log_config.enabled {
@@ -43,22 +135,30 @@ impl ConfigFile {
         } else {
             println!("  Logging: disabled")
         }
-        let enabled_sinks : Vec<&SinkTypeConfig> = self.sink.iter().filter(|sink_config| {
-                                                        match sink_config {
-                                                            SinkTypeConfig::DataFile(config) => config.enabled,
-                                                            SinkTypeConfig::InfluxDb(config)=> config.enabled,
-                                                            SinkTypeConfig::Mqtt(config) => config.enabled
-                                                        }}).collect();
-        if enabled_sinks.len() == 0 {
+        match self.spool {
+            Some(spool_config) if spool_config.enabled => println!(
+                "  Spool: directory={}, max-size={} MB, max-age={}",
+                spool_config.directory,
+                spool_config.max_size_mb(),
+                spool_config.max_age_hours.map_or("-".into(), |hours| format!("{} h", hours))
+            ),
+            _ => println!("  Spool: disabled"),
+        }
+        let enabled_sinks: Vec<&SinkConfig> = self.sink.iter().filter(|sink_config| sink_config.enabled).collect();
+        if enabled_sinks.is_empty() {
             println!("  Sinks: none");
         } else {
             println!("  Sinks:");
             for sink_config in enabled_sinks {
-                let ser_sink_config = match sink_config {
-                    SinkTypeConfig::DataFile(config) => format!("Data File: {}", serde_json::to_string(config).unwrap()),
-                    SinkTypeConfig::InfluxDb(config)=>  format!("InfluxDB:  {}", serde_json::to_string(config).unwrap()),
-                    SinkTypeConfig::Mqtt(config) =>     format!("MQTT:      {}", serde_json::to_string(config).unwrap())
+                let mut settings = sink_config.settings.clone();
+                for key in SECRET_SETTINGS {
+                    settings.remove(key);
+                }
+                let label = match &sink_config.options.name {
+                    Some(name) => format!("{} ({})", name, sink_config.sink_type),
+                    None => sink_config.sink_type.clone(),
                 };
+                let ser_sink_config = format!("{}: {}", label, serde_json::to_string(&settings).unwrap());
                 println!("     {:?}", ser_sink_config);
             }
         }
@@ -73,58 +173,415 @@ pub struct LogConfig {
     pub level: Option<String>,
 }
 
+/// On-disk queue of the readings which could not be published.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct DataFileConfig {
+pub struct SpoolConfig {
+    pub enabled: bool,
+    /// Base directory, every sink spools into a subdirectory.
+    pub directory: String,
+    /// Maximum size of the queue of a sink, the oldest readings are dropped first.
+    #[serde(rename = "max-size-mb")]
+    pub max_size_mb: Option<u64>,
+    /// Readings older than this are dropped instead of replayed.
+    #[serde(rename = "max-age-hours")]
+    pub max_age_hours: Option<u64>,
+}
+
+impl SpoolConfig {
+    pub fn max_size_mb(&self) -> u64 {
+        self.max_size_mb.unwrap_or(DEFAULT_SPOOL_MAX_SIZE_MB)
+    }
+}
+
+/// A `[[sink]]` entry. Besides `type`, `enabled` and the [`SinkOptions`]
+/// the settings depend on the sink type, see [`SinkConfig::parse`].
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SinkConfig {
+    #[serde(rename = "type")]
+    pub sink_type: String,
     pub enabled: bool,
+    #[serde(flatten)]
+    pub options: SinkOptions,
+    /// The settings of the sink type.
+    #[serde(flatten)]
+    pub settings: toml::Table,
+}
+
+impl SinkConfig {
+    /// Name of the sink in logs and status output: the configured `name` or the type.
+    pub fn label(&self) -> &str {
+        self.options.name.as_deref().unwrap_or(&self.sink_type)
+    }
+
+    /// Name of the spool directory of the sink at `position` (starting at 1):
+    /// the sink name or the position and type.
+    pub fn spool_directory(&self, position: usize) -> String {
+        match &self.options.name {
+            Some(name) => slugify(name),
+            None => format!("{}-{}", position, slugify(&self.sink_type)),
+        }
+    }
+
+    /// Parses the settings of the sink type, e.g. into an [`InfluxDbConfig`].
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, ConfigError> {
+        toml::Value::Table(self.settings.clone())
+            .try_into()
+            .map_err(|error| ConfigError::Invalid(format!("{} sink: {}", self.sink_type, error)))
+    }
+}
+
+/// Publish timeout of a sink when not configured otherwise.
+const DEFAULT_SINK_TIMEOUT_MS: u64 = 30_000;
+
+/// Settings which are common to all sink types.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SinkOptions {
+    /// Unique name to tell sinks of the same type apart.
+    pub name: Option<String>,
+    /// Maximum time to publish a reading, readings which time out are spooled.
+    #[serde(rename = "timeout-ms")]
+    pub timeout_ms: Option<u64>,
+    /// Abort the startup if the sink cannot be created.
+    #[serde(default)]
+    pub required: bool,
+    /// Readings which are published to the sink.
+    #[serde(default)]
+    pub filter: SinkFilter,
+}
+
+impl SinkOptions {
+    /// The configured or the default publish timeout.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.unwrap_or(DEFAULT_SINK_TIMEOUT_MS))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DataFileConfig {
     pub file: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct InfluxDbConfig {
-    pub enabled: bool,
     pub url: String,
+    #[serde(default)]
+    pub api: InfluxDbApi,
+    /// Organization, required by the v2 API.
+    pub org: Option<String>,
+    /// Bucket (v2) or database (v1).
     pub bucket: String,
-    pub token: String,
+    pub token: Option<String>,
+    /// Basic authentication (v1), replaces the token.
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    #[serde(rename = "retention-policy")]
+    pub retention_policy: Option<String>,
+    pub consistency: Option<InfluxDbConsistency>,
+    /// PEM file with additional CA certificates for HTTPS.
+    #[serde(rename = "ca-file")]
+    pub ca_file: Option<String>,
+    /// Accept invalid server certificates, e.g. self-signed ones.
+    #[serde(rename = "tls-skip-verify", default)]
+    pub tls_skip_verify: bool,
+    /// Number of points written per request (default: 1, no batching).
+    #[serde(rename = "batch-size")]
+    pub batch_size: Option<usize>,
+    /// Maximum time a point is buffered before the batch is written.
+    #[serde(rename = "batch-delay-ms")]
+    pub batch_delay_ms: Option<u64>,
+    /// Maximum number of buffered points, further readings are spooled.
+    #[serde(rename = "max-buffered")]
+    pub max_buffered: Option<usize>,
+    /// Retries of a write failing with a transient error (default: 3).
+    #[serde(rename = "max-retries")]
+    pub max_retries: Option<u32>,
+    /// Initial delay between retries, doubled with every retry (default: 500).
+    #[serde(rename = "retry-delay-ms")]
+    pub retry_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub precision: InfluxDbPrecision,
+    /// Never implemented, rejected when the configuration is validated.
+    #[serde(skip_serializing)]
     pub detect_start_time: Option<bool>,
+    #[serde(default)]
+    pub schema: InfluxDbSchema,
+    /// Measurement prefix of the legacy schema, the sensor ID is appended.
     #[serde(rename = "measurement-base")]
-    pub measurement_base: String
+    pub measurement_base: Option<String>,
+    /// Measurement name template, replaces `measurement-base.{id}` of the
+    /// legacy schema and the sensor kind of the tagged schema.
+    pub measurement: Option<Template>,
+}
+
+/// Write API of the InfluxDB server.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxDbApi {
+    /// `/write` of InfluxDB 1.x, also served by the 2.x compatibility layer.
+    #[default]
+    V1,
+    /// Native `/api/v2/write` of InfluxDB 2.x.
+    V2,
+}
+
+/// Write consistency of InfluxDB Enterprise clusters (v1).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxDbConsistency {
+    Any,
+    One,
+    Quorum,
+    All,
+}
+
+/// Timestamp precision of the written points.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxDbPrecision {
+    Ns,
+    Us,
+    #[default]
+    Ms,
+    S,
+}
+
+/// Layout of the points written to InfluxDB.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxDbSchema {
+    /// One measurement per sensor (`measurement-base.<id>`) with a `value` field.
+    #[default]
+    Legacy,
+    /// One measurement per sensor kind (e.g. `temperature`), sensors are
+    /// distinguished by the `sensor_id`, `name` and tag tags.
+    Tagged,
+}
+
+/// Topic layout of the readings published to MQTT.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttConvention {
+    /// A JSON message per reading at `topic-base/<name>` or the `topic` template.
+    #[default]
+    Topics,
+    /// Homie convention: the base station is a device, every sensor a node
+    /// with a property of its kind.
+    Homie,
+}
+
+/// Encoding of the MQTT payload of a reading.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttPayloadFormat {
+    /// JSON object of the selected fields.
+    #[default]
+    Json,
+    /// The bare value, e.g. `21.5`.
+    Plain,
+    /// The selected fields as comma-separated line.
+    Csv,
+    /// CBOR map of the selected fields.
+    Cbor,
+    /// MessagePack map of the selected fields.
+    #[serde(alias = "msgpack")]
+    MessagePack,
+}
+
+/// Format of the reading timestamp in the MQTT payload.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampFormat {
+    /// RFC 3339 text, e.g. `2024-07-01T12:00:00+02:00`.
+    #[default]
+    Rfc3339,
+    /// Seconds since the Unix epoch.
+    EpochSeconds,
+    /// Milliseconds since the Unix epoch.
+    EpochMillis,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MqttConfig {
-    pub enabled: bool,
     pub host: String,
     pub port: u16,
     #[serde(rename = "topic-base")]
     pub topic_base: String,
-}
-
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Deserialize, Clone)]
-#[serde(tag = "type")]
-pub enum SinkTypeConfig {
-    DataFile(DataFileConfig),
-    #[serde(rename = "InfluxDB")]
-    InfluxDb(InfluxDbConfig),
-    #[serde(rename = "MQTT")]
-    Mqtt(MqttConfig),
+    /// Topic template, replaces `topic-base/{name}`.
+    pub topic: Option<Template>,
+    /// Client ID, must be unique per broker (default `arexx-mqtt`).
+    #[serde(rename = "client-id")]
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// File which contains the password, e.g. a secret of the service manager.
+    #[serde(rename = "password-file")]
+    pub password_file: Option<String>,
+    /// Start without the subscriptions and queued messages of a previous
+    /// session (default true).
+    #[serde(rename = "clean-session")]
+    pub clean_session: Option<bool>,
+    #[serde(default)]
+    pub tls: bool,
+    /// CA certificates (PEM) of the broker, the platform certificates if not set.
+    #[serde(rename = "ca-file")]
+    pub ca_file: Option<String>,
+    /// Client certificate and key (PEM) for client authentication.
+    #[serde(rename = "client-cert")]
+    pub client_cert: Option<String>,
+    #[serde(rename = "client-key")]
+    pub client_key: Option<String>,
+    /// Quality of service of the readings: 0, 1 (default) or 2.
+    pub qos: Option<u8>,
+    /// Publish the readings as retained messages (default false).
+    pub retain: Option<bool>,
+    /// Keep-alive interval in seconds, 0 disables it (default 5).
+    #[serde(rename = "keep-alive-secs")]
+    pub keep_alive_secs: Option<u64>,
+    /// Topic of the availability of arexx-tap, `online` or `offline`
+    /// (default `<topic-base>/status`).
+    #[serde(rename = "status-topic")]
+    pub status_topic: Option<String>,
+    /// Publish the availability of every sensor, which is `offline` without
+    /// a reading within this time.
+    #[serde(rename = "sensor-timeout-secs")]
+    pub sensor_timeout_secs: Option<u64>,
+    /// Announce the sensors to Home Assistant with MQTT discovery.
+    #[serde(rename = "homeassistant-discovery", default)]
+    pub homeassistant_discovery: bool,
+    /// Topic prefix of the discovery messages (default `homeassistant`).
+    #[serde(rename = "discovery-prefix")]
+    pub discovery_prefix: Option<String>,
+    #[serde(default)]
+    pub convention: MqttConvention,
+    /// Base topic of the Homie devices (default `homie`).
+    #[serde(rename = "homie-prefix")]
+    pub homie_prefix: Option<String>,
+    /// Homie device ID of the base station (default `arexx-<station>`).
+    #[serde(rename = "homie-device-id")]
+    pub homie_device_id: Option<String>,
+    #[serde(default)]
+    pub format: MqttPayloadFormat,
+    /// Fields of the payload: `time`, `value`, `sensor`, `name`, `kind`,
+    /// `station`, `tags` (all sensor tags) or a tag name. Default `time`,
+    /// `value` and `tags`, CSV `time` and `value`.
+    pub fields: Option<Vec<String>>,
+    #[serde(rename = "timestamp-format", default)]
+    pub timestamp_format: TimestampFormat,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SensorConfig {
     pub id: u16,
     pub name: String,
+    pub kind: Option<String>,
     #[serde(rename = "temperature-scaling")]
-    pub temperature_scaling: Cell<Option<f32>>
+    pub temperature_scaling: Cell<Option<f32>>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Unit of the values, announced to Home Assistant (default `°C` for
+    /// temperature and `%` for humidity sensors).
+    pub unit: Option<String>,
+    /// Home Assistant device class (default: the kind of temperature and
+    /// humidity sensors).
+    #[serde(rename = "device-class")]
+    pub device_class: Option<String>,
+    /// Overrides the `retain` setting of the MQTT sinks for this sensor.
+    #[serde(rename = "mqtt-retain")]
+    pub mqtt_retain: Option<bool>,
 }
 
-pub fn read_config_file(config_file: PathBuf) -> Result<ConfigFile> {
-    let config_str = std::fs::read_to_string(config_file)
-        .context("Failed to open file")
-        .unwrap();
-    let config = toml::from_str::<ConfigFile>(&config_str)
-        .context("Failed to read toml configuration")
-        .unwrap();
+/// Reads the configuration file and validates it with the sink types of
+/// `registry`.
+pub fn read_config_file(config_file: PathBuf, registry: &SinkRegistry) -> Result<ConfigFile, ConfigError> {
+    if !config_file.exists() {
+        return Err(ConfigError::NotFound(config_file));
+    }
+    let config_str = std::fs::read_to_string(&config_file)
+        .map_err(|source| ConfigError::Read { path: config_file, source })?;
+    let config = toml::from_str::<ConfigFile>(&config_str)?;
+    config.validate_with(registry)?;
 
     Ok(config)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_sensors(names: &[&str]) -> ConfigFile {
+        let sensors: Vec<String> = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| format!("[[sensors]]\nid = {}\nname = \"{}\"\n", index + 1, name))
+            .collect();
+        toml::from_str(&format!("sink = []\n{}", sensors.concat())).unwrap()
+    }
+
+    #[test]
+    fn accepts_distinct_sensor_names() {
+        assert!(config_with_sensors(&["Living room", "Kitchen"]).validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_sensor_name_without_letters_or_digits() {
+        assert!(config_with_sensors(&["Kitchen", "--"]).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_sensor_names_with_the_same_slug() {
+        assert!(config_with_sensors(&["Living room", "living-room"]).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_sink_templates_with_undefined_tags() {
+        let config = |topic: &str| -> ConfigFile {
+            toml::from_str(&format!(
+                r#"
+                [[sensors]]
+                id = 1
+                name = "Cellar"
+                tags = {{ room = "cellar" }}
+                [[sink]]
+                type = "MQTT"
+                enabled = true
+                host = "localhost"
+                port = 1883
+                topic-base = "arexx"
+                topic = "{}"
+                "#,
+                topic
+            ))
+            .unwrap()
+        };
+        assert!(config("home/{room}/{name}").validate().is_ok());
+        assert!(config("home/{floor}/{name}").validate().is_err());
+    }
+
+    fn config_with_sinks(sinks: &[(&str, Option<&str>)]) -> ConfigFile {
+        let sinks: Vec<String> = sinks
+            .iter()
+            .map(|(sink_type, name)| match name {
+                Some(name) => format!("[[sink]]\ntype = \"{}\"\nenabled = false\nname = \"{}\"\n", sink_type, name),
+                None => format!("[[sink]]\ntype = \"{}\"\nenabled = false\n", sink_type),
+            })
+            .collect();
+        toml::from_str(&format!("sensors = []\n{}", sinks.concat())).unwrap()
+    }
+
+    #[test]
+    fn names_spool_directory_after_name_or_position() {
+        let config = config_with_sinks(&[("InfluxDB", None), ("MQTT", Some("Local broker"))]);
+        assert_eq!(config.sink[0].spool_directory(1), "1-influxdb");
+        assert_eq!(config.sink[1].spool_directory(2), "local-broker");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_sinks_with_the_same_spool_directory() {
+        assert!(config_with_sinks(&[("InfluxDB", Some("Local")), ("MQTT", Some("local"))]).validate().is_err());
+        assert!(config_with_sinks(&[("InfluxDB", None), ("MQTT", Some("1-InfluxDB"))]).validate().is_err());
+        assert!(config_with_sinks(&[("InfluxDB", None), ("InfluxDB", None)]).validate().is_ok());
+    }
+}
@@ -0,0 +1,267 @@
+//! Raw protocol console.
+//!
+//! Sends hex packets to the write endpoint of the device and dumps the
+//! replies. Every session is saved to a capture file.
+//!
+//! Input lines are hex packets (`03`, `04 00 11 22 33` or `0400112233`),
+//! padded with zeros to the packet size. Besides packets the console knows
+//! the commands `read` (read replies without writing), `wait <millis>` and
+//! `quit`. Text after `#` is ignored.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use arexx_tap::config::ConfigFile;
+use arexx_tap::error::DeviceError;
+use arexx_tap::profile::{DeviceProfile, PACKET_SIZE};
+use arexx_tap::usb::{UsbDevice, UsbInner};
+use chrono::Local;
+use clap::Args;
+
+const DEVICE_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REPLIES: usize = 16;
+
+#[derive(Args, Debug)]
+pub(crate) struct ConsoleOptions {
+    /// Read the packets from a script file instead of stdin
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// Capture file of the session (default: arexx-capture-<timestamp>.txt)
+    #[arg(long)]
+    capture: Option<PathBuf>,
+
+    /// Time to wait for replies after a packet was sent, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    read_timeout: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum ConsoleCommand {
+    Send(Vec<u8>),
+    Read,
+    Wait(Duration),
+    Quit,
+}
+
+fn parse_line(line: &str) -> Result<Option<ConsoleCommand>> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("quit") | Some("exit") => return Ok(Some(ConsoleCommand::Quit)),
+        Some("read") => return Ok(Some(ConsoleCommand::Read)),
+        Some("wait") => {
+            let millis = words.next().context("usage: wait <millis>")?.parse::<u64>().context("invalid wait time")?;
+            return Ok(Some(ConsoleCommand::Wait(Duration::from_millis(millis))));
+        }
+        _ => {}
+    }
+
+    let digits: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid hex packet: `{}`", line);
+    }
+    if digits.len() % 2 != 0 {
+        bail!("odd number of hex digits: `{}`", line);
+    }
+    let packet = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .with_context(|| format!("invalid hex packet: `{}`", line))?;
+    if packet.len() > PACKET_SIZE {
+        bail!("packet exceeds {} bytes", PACKET_SIZE);
+    }
+    Ok(Some(ConsoleCommand::Send(packet)))
+}
+
+/// Formats 16 bytes per line with offset and ASCII column.
+fn hexdump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(n, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            format!("  {:04x}  {:<47}  |{}|", n * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+struct Session {
+    capture: File,
+}
+
+impl Session {
+    fn log(&mut self, line: &str) -> Result<()> {
+        println!("{}", line);
+        writeln!(self.capture, "{}", line).context("cannot write capture file")?;
+        Ok(())
+    }
+
+    fn log_packet(&mut self, direction: &str, packet: &[u8]) -> Result<()> {
+        let header = format!("{} {} {} bytes", Local::now().to_rfc3339(), direction, packet.len());
+        self.log(&header)?;
+        self.log(&hexdump(packet))
+    }
+
+    fn read_replies(&mut self, usb_inner: &UsbInner, timeout: Duration) -> Result<()> {
+        for _ in 0..MAX_REPLIES {
+            let mut buf: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+            match usb_inner.read_packet(&mut buf, timeout) {
+                Ok(len) => self.log_packet("<", &buf[..len])?,
+                Err(DeviceError::Timeout) => return Ok(()),
+                Err(error) => return self.log(&format!("{} ! read failed: {}", Local::now().to_rfc3339(), error)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn wait_for_device(usb: &std::sync::Mutex<UsbDevice>) -> Result<()> {
+    let started = Instant::now();
+    while usb.lock().unwrap().inner.is_none() {
+        if started.elapsed() > DEVICE_WAIT_TIMEOUT {
+            bail!("Arexx device not available");
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+pub(crate) fn run(config: &ConfigFile, options: ConsoleOptions) -> Result<()> {
+    let profile = config.device_profile()?;
+    let usb = UsbDevice::new(&profile)?;
+    let result = run_session(&profile, &usb, options);
+    usb.lock().unwrap().stop();
+    result
+}
+
+fn run_session(profile: &DeviceProfile, usb: &std::sync::Mutex<UsbDevice>, options: ConsoleOptions) -> Result<()> {
+    wait_for_device(usb)?;
+
+    let capture_path = options
+        .capture
+        .unwrap_or_else(|| PathBuf::from(format!("arexx-capture-{}.txt", Local::now().format("%Y%m%dT%H%M%S"))));
+    let capture = File::create(&capture_path)
+        .with_context(|| format!("can't create capture file {}", capture_path.display()))?;
+    let mut session = Session { capture };
+    session.log(&format!("# arexx-tap console session, device {}", profile))?;
+
+    let interactive = options.script.is_none() && io::stdin().is_terminal();
+    let input: Box<dyn BufRead> = match &options.script {
+        Some(script) => Box::new(BufReader::new(
+            File::open(script).with_context(|| format!("can't open script {}", script.display()))?,
+        )),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+    let read_timeout = Duration::from_millis(options.read_timeout);
+
+    if interactive {
+        print!("> ");
+        io::stdout().flush()?;
+    }
+    for line in input.lines() {
+        let line = line?;
+        let command = match parse_line(&line) {
+            Ok(command) => command,
+            Err(error) if interactive => {
+                eprintln!("{:#}", error);
+                None
+            }
+            Err(error) => return Err(error),
+        };
+
+        match command {
+            Some(ConsoleCommand::Send(packet)) => {
+                let usb = usb.lock().unwrap();
+                let usb_inner = usb.inner.as_ref().context("Arexx device detached")?;
+                let mut buf: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+                buf[..packet.len()].copy_from_slice(&packet);
+                session.log_packet(">", &buf)?;
+                match usb_inner.write_packet(&buf, WRITE_TIMEOUT) {
+                    Ok(_) => session.read_replies(usb_inner, read_timeout)?,
+                    Err(error) => session.log(&format!("{} ! write failed: {}", Local::now().to_rfc3339(), error))?,
+                }
+            }
+            Some(ConsoleCommand::Read) => {
+                let usb = usb.lock().unwrap();
+                let usb_inner = usb.inner.as_ref().context("Arexx device detached")?;
+                session.read_replies(usb_inner, read_timeout)?
+            }
+            Some(ConsoleCommand::Wait(duration)) => std::thread::sleep(duration),
+            Some(ConsoleCommand::Quit) => break,
+            None => {}
+        }
+
+        if interactive {
+            print!("> ");
+            io::stdout().flush()?;
+        }
+    }
+
+    println!("session captured in {}", capture_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_packets_with_whitespace() {
+        let command = parse_line("  03 04 0a\tFF # set clock").unwrap();
+        assert_eq!(command, Some(ConsoleCommand::Send(vec![0x03, 0x04, 0x0a, 0xff])));
+        assert_eq!(parse_line("0304").unwrap(), Some(ConsoleCommand::Send(vec![0x03, 0x04])));
+    }
+
+    #[test]
+    fn skips_empty_and_comment_lines() {
+        assert_eq!(parse_line("").unwrap(), None);
+        assert_eq!(parse_line(" \t ").unwrap(), None);
+        assert_eq!(parse_line("# comment").unwrap(), None);
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_line("read").unwrap(), Some(ConsoleCommand::Read));
+        assert_eq!(parse_line("exit").unwrap(), Some(ConsoleCommand::Quit));
+        assert_eq!(parse_line("wait 250").unwrap(), Some(ConsoleCommand::Wait(Duration::from_millis(250))));
+        assert!(parse_line("wait").is_err());
+        assert!(parse_line("wait soon").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_hex_packets() {
+        assert!(parse_line("03 0").is_err(), "odd number of digits");
+        assert!(parse_line("03 0g").is_err(), "invalid digit");
+        assert!(parse_line("03 +4").is_err(), "sign");
+        assert!(parse_line(&"00".repeat(PACKET_SIZE + 1)).is_err(), "too long");
+        assert!(parse_line(&"00".repeat(PACKET_SIZE)).is_ok());
+    }
+
+    #[test]
+    fn formats_hexdump() {
+        let data: Vec<u8> = (0x30..0x42).collect();
+        assert_eq!(
+            hexdump(&data),
+            format!(
+                "  0000  30 31 32 33 34 35 36 37 38 39 3a 3b 3c 3d 3e 3f  |0123456789:;<=>?|\n  0010  {:<47}  |@A|",
+                "40 41"
+            )
+        );
+        assert_eq!(hexdump(&[0x00, 0x20, 0x7f]), format!("  0000  {:<47}  |. .|", "00 20 7f"));
+        assert_eq!(hexdump(&[]), "");
+    }
+}
@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::Error;
+
+pub use crate::sink::{DataFileError, InfluxDbError, MqttError};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Top-level error of the crate.
+///
+/// Every error is classified as either retryable (the operation may succeed
+/// when tried again later) or fatal (it won't succeed without intervention).
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Sink(#[from] SinkError),
+}
+
+impl Error {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Device(error) => error.is_retryable(),
+            Error::Decode(error) => error.is_retryable(),
+            Error::Config(_) => false,
+            Error::Sink(error) => error.is_retryable(),
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        !self.is_retryable()
+    }
+}
+
+/// Errors of the USB communication with the base station.
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("USB transfer timed out")]
+    Timeout,
+    #[error("Arexx device detached")]
+    Detached,
+    #[error("USB error: {0}")]
+    Usb(rusb::Error),
+    #[error("failed to register USB hotplug listener: {0}")]
+    Hotplug(rusb::Error),
+    #[error("no bulk read/write endpoints found")]
+    NoEndpoints,
+}
+
+impl DeviceError {
+    /// Timeouts and transient transfer errors are retryable. A detached device
+    /// is retryable as well: it is picked up again once it is reconnected.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DeviceError::Timeout | DeviceError::Detached => true,
+            DeviceError::Usb(error) => matches!(
+                error,
+                rusb::Error::Io | rusb::Error::Busy | rusb::Error::Overflow | rusb::Error::Pipe | rusb::Error::Interrupted
+            ),
+            DeviceError::Hotplug(_) | DeviceError::NoEndpoints => false,
+        }
+    }
+}
+
+impl From<rusb::Error> for DeviceError {
+    fn from(error: rusb::Error) -> Self {
+        match error {
+            rusb::Error::Timeout => DeviceError::Timeout,
+            rusb::Error::NoDevice => DeviceError::Detached,
+            error => DeviceError::Usb(error),
+        }
+    }
+}
+
+impl From<rusb::Error> for Error {
+    fn from(error: rusb::Error) -> Self {
+        Error::Device(error.into())
+    }
+}
+
+/// Errors decoding a packet received from the base station.
+///
+/// A malformed packet is skipped, decoding errors are therefore retryable.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("packet too short: {0} bytes")]
+    ShortPacket(usize),
+    #[error("invalid tuple length: {0} bytes")]
+    InvalidTupleLength(usize),
+}
+
+impl DecodeError {
+    pub fn is_retryable(&self) -> bool {
+        true
+    }
+}
+
+/// Errors loading the configuration file. Always fatal.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("config file `{}` not found", .0.display())]
+    NotFound(PathBuf),
+    #[error("failed to read config file `{}`: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("failed to read toml configuration: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+/// Errors of the sinks, one variant per built-in sink type, the publish
+/// timeout and errors of sinks registered by library users.
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    DataFile(#[from] DataFileError),
+    #[error(transparent)]
+    InfluxDb(#[from] InfluxDbError),
+    #[error(transparent)]
+    Mqtt(#[from] MqttError),
+    #[error("publishing timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("{source}")]
+    Other {
+        source: Box<dyn std::error::Error + Send + Sync>,
+        retryable: bool,
+    },
+}
+
+impl SinkError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SinkError::Config(_) => false,
+            SinkError::DataFile(error) => error.is_retryable(),
+            SinkError::InfluxDb(error) => error.is_retryable(),
+            SinkError::Mqtt(error) => error.is_retryable(),
+            SinkError::Timeout(_) => true,
+            SinkError::Other { retryable, .. } => *retryable,
+        }
+    }
+}
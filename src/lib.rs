@@ -0,0 +1,36 @@
+//! Reading of temperature values from an Arexx base station.
+//!
+//! The crate exposes the USB device handling ([`usb`]), the record decoder
+//! ([`arexx`]), the configuration ([`config`]), the storage sinks ([`sink`])
+//! and their on-disk queue ([`spool`]). Readings of a connected base station
+//! are consumed as an async stream:
+//!
+//! ```no_run
+//! use arexx_tap::arexx::Arexx;
+//! use arexx_tap::config::ConfigFile;
+//! use tokio_stream::StreamExt;
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let arexx = Arexx::new(ConfigFile::default(), None)?;
+//! let mut readings = arexx.stream();
+//! while let Some(reading) = readings.next().await {
+//!     println!("{}", reading);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Errors are typed (see [`error`]) and classified as retryable or fatal.
+//! [`Arexx::try_stream`] passes read errors on to the caller.
+
+pub mod arexx;
+pub mod config;
+pub mod error;
+pub mod profile;
+pub mod sink;
+pub mod spool;
+pub mod template;
+pub mod usb;
+
+pub use crate::arexx::{Arexx, ReadingStream, TemperatureReading as Reading, TryReadingStream};
+pub use crate::error::{Error, Result};
@@ -1,14 +1,14 @@
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
 
-use crate::config::SinkTypeConfig::{DataFile, InfluxDb, Mqtt};
-use crate::config::{read_config_file, ConfigFile, LogConfig};
-use crate::sink::{DataFileSink, InfluxDbSink, MqttSink, Sink, SinkType};
 use anyhow::{bail, Context, Result};
-use arexx::ArexxResult;
-use clap::{arg, Parser};
+use arexx_tap::arexx::Arexx;
+use arexx_tap::config::{read_config_file, ConfigFile, LogConfig};
+use arexx_tap::error::{DeviceError, Error};
+use arexx_tap::sink::{SinkRegistry, SinkWorker, SpooledSink};
+use clap::{Parser, Subcommand};
 use time::macros::format_description;
+use tokio_stream::StreamExt;
 use tracing::level_filters::LevelFilter;
 use tracing::Level;
 use tracing_appender::non_blocking::WorkerGuard;
@@ -18,21 +18,29 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, Layer};
 
-mod arexx;
-mod config;
-mod sink;
-mod usb;
+use crate::console::ConsoleOptions;
 
-const POLL_INTERVAL_SECONDS: u64 = 1;
+mod console;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub(crate) struct CliOptions {
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config: Option<PathBuf>,
 
     #[arg(long)]
     start_time: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Send raw hex packets to the device and dump the replies
+    Console(ConsoleOptions),
+    /// Validate the configuration and create the sinks without reading the device
+    Check,
 }
 
 fn configure_tracing(opts: Option<LogConfig>) -> Result<Vec<WorkerGuard>> {
@@ -83,94 +91,141 @@ fn configure_tracing(opts: Option<LogConfig>) -> Result<Vec<WorkerGuard>> {
     Ok(guards)
 }
 
-fn assemble_sinks(config: &ConfigFile) -> Vec<SinkType> {
-    let mut sinks: Vec<SinkType> = Vec::new();
-    for sink_type in &config.sink {
-        match sink_type {
-            DataFile(config) => {
-                if let Ok(Some(sink)) = DataFileSink::new(config) {
-                    sinks.push(SinkType::DataFile(Box::new(sink)))
-                }
-            }
-            InfluxDb(config) => {
-                if let Ok(Some(sink)) = InfluxDbSink::new(config) {
-                    sinks.push(SinkType::InfluxDb(Box::new(sink)))
-                }
-            }
-            Mqtt(config) => {
-                if let Ok(Some(sink)) = MqttSink::new(config) {
-                    sinks.push(SinkType::Mqtt(Box::new(sink)))
-                }
-            }
-        }
+/// Creates the enabled sinks and reports every sink which cannot be created.
+/// Fails if one of them is required.
+fn create_sinks(registry: &SinkRegistry, config: &ConfigFile) -> Result<(Vec<SpooledSink>, usize)> {
+    let (sinks, failures) = registry.assemble(config);
+    for failure in &failures {
+        eprintln!("Error: cannot create {}", failure);
+    }
+    let required_failures = failures.iter().filter(|failure| failure.required).count();
+    if required_failures > 0 {
+        bail!("{} required sink(s) could not be created. Aborting.", required_failures);
     }
+    Ok((sinks, failures.len()))
+}
 
-    sinks
+/// Stops the sink workers after they published the pending readings and
+/// wrote the readings which are still buffered in the sinks.
+async fn shutdown_sinks(workers: Vec<SinkWorker>) {
+    for worker in workers {
+        worker.shutdown().await;
+    }
+}
+
+/// Completes on Ctrl-C or, on Unix, on SIGTERM (e.g. from systemd).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("cannot install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli_options = CliOptions::parse();
 
+    let registry = SinkRegistry::default();
     let config: ConfigFile;
     if let Some(config_file) = cli_options.config {
-        if !config_file.exists() {
-            bail!(format!(
-                "config file `{}` not found. Aborting.",
-                config_file.to_str().unwrap()
-            ));
-        }
-        config = read_config_file(config_file)
-        .context("error reading config file")
-        .unwrap();
+        config = read_config_file(config_file, &registry).context("error reading config file. Aborting.")?;
     } else {
         config = ConfigFile::default();
     }
 
     let _guards = configure_tracing(config.log.clone()).context("failed initializing tracing");
 
+    match cli_options.command {
+        Some(Command::Console(console_options)) => {
+            return tokio::task::spawn_blocking(move || console::run(&config, console_options)).await?;
+        }
+        Some(Command::Check) => {
+            ConfigFile::print(config.clone());
+            println!();
+            let (sinks, failures) = create_sinks(&registry, &config)?;
+            for sink in &sinks {
+                if let Err(error) = sink.shutdown().await {
+                    tracing::warn!("error shutting down {}: {}", sink, error);
+                }
+            }
+            if failures > 0 {
+                bail!("{} sink(s) could not be created", failures);
+            }
+            let sink_names: Vec<String> = sinks.iter().map(ToString::to_string).collect();
+            println!("Configuration OK, sinks: {}", if sink_names.is_empty() { "none".into() } else { sink_names.join(", ") });
+            return Ok(());
+        }
+        None => {}
+    }
+
     println!("Starting arexx-tap");
     ConfigFile::print(config.clone());
     println!();
 
-    let mut arexx = arexx::Arexx::new(config.clone(), cli_options.start_time)
-        .context("failed to create Arexx instance")
-        .unwrap();
-    let sinks = assemble_sinks(&config);
+    let (sinks, failures) = create_sinks(&registry, &config)?;
+    if failures > 0 && sinks.is_empty() {
+        eprintln!("Warning: no sink available, readings are only printed");
+    }
+    let arexx = Arexx::new(config.clone(), cli_options.start_time)
+        .context("failed to create Arexx instance")?;
+    let workers: Vec<SinkWorker> = sinks.into_iter().map(SinkWorker::spawn).collect();
 
+    let mut readings = arexx.try_stream();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        match arexx.read_record() {
-            Ok(ArexxResult::Temperature(reading)) => {
-                tracing::debug!("read record: {:?}", &reading);
-                if sinks.len() == 0 {
-                    println!("{}", reading);
-                } else {
-                    for sink_type in &sinks {
-                        let publish_result = match sink_type {
-                            SinkType::DataFile(sink) => sink.publish(&reading).await,
-                            SinkType::InfluxDb(sink) => sink.publish(&reading).await,
-                            SinkType::Mqtt(sink) => sink.publish(&reading).await,
-                        };
-                        match publish_result {
-                            Ok(_) => tracing::trace!("published {} to {}", &reading, sink_type),
-                            Err(error) => tracing::error!("error publishing {} to {}: {}", &reading, sink_type, error)
-                        }
-                    }
-                }
+        let read_result = tokio::select! {
+            read_result = readings.next() => match read_result {
+                Some(read_result) => read_result,
+                None => break,
             },
-            Ok(ArexxResult::NotAvailable) => {
-                tracing::info!("Arexx device not available. Sleep 5 secs");
-                std::thread::sleep(Duration::from_secs(5));
+            _ = &mut shutdown => {
+                tracing::info!("shutting down");
+                break;
+            }
+        };
+        let reading = match read_result {
+            Ok(reading) => reading,
+            Err(Error::Device(DeviceError::Timeout)) => {
+                tracing::debug!("no data from Arexx device within timeout");
+                continue;
             }
-            Ok(_) => {
-                tracing::debug!("Ignore other data");
+            Err(Error::Device(DeviceError::Detached)) => {
+                tracing::warn!("Arexx device detached, waiting for reconnect");
+                continue;
             }
-            Err(error) => {
+            Err(error) if error.is_retryable() => {
                 tracing::error!("error reading record: {}", error);
+                continue;
+            }
+            Err(error) => {
+                tracing::error!("fatal error reading record: {}", error);
+                drop(readings);
+                shutdown_sinks(workers).await;
+                bail!("fatal error reading record: {}", error);
+            }
+        };
+
+        if workers.is_empty() {
+            println!("{}", reading);
+        } else {
+            for worker in &workers {
+                worker.send(&reading);
             }
         }
-        std::thread::sleep(Duration::from_secs(POLL_INTERVAL_SECONDS));
     }
-    // unreachable:
-    // Ok(())
-}
\ No newline at end of file
+
+    // stops the USB reader before the sinks are drained
+    drop(readings);
+    shutdown_sinks(workers).await;
+    Ok(())
+}
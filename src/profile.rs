@@ -0,0 +1,197 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::ConfigError;
+
+/// Packet size of the Arexx data loggers (request and reply).
+pub const PACKET_SIZE: usize = 64;
+
+/// Arrangement of the sensor data tuples within a reply packet.
+///
+/// Tuples start at offset 1 of a reply packet with their length byte
+/// (see `resources/PROTOCOL.md`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TupleLayout {
+    /// Only the first 9-byte tuple of a packet is evaluated.
+    Single,
+    /// A sequence of 9- or 10-byte tuples, terminated by a 0-byte tuple.
+    Sequence,
+}
+
+/// Command packets written to the device after it was connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitCommand {
+    /// Set the logger clock (packet type 0x04).
+    SetClock,
+}
+
+/// Parameters of a base station model.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceProfile {
+    pub name: String,
+    pub vid: u16,
+    pub pid: u16,
+    /// Address of the bulk IN endpoint, detected when not set.
+    pub read_endpoint: Option<u8>,
+    /// Address of the bulk OUT endpoint, detected when not set.
+    pub write_endpoint: Option<u8>,
+    pub init_sequence: Vec<InitCommand>,
+    pub tuple_layout: TupleLayout,
+}
+
+/// Name of the profile which is built from the `[device]` settings only.
+pub const CUSTOM_PROFILE: &str = "custom";
+
+const AREXX_VID: u16 = 0x0451;
+const AREXX_PID: u16 = 0x3211;
+
+/// The built-in profiles, only models whose parameters were verified with a
+/// device. Other models are configured with the `custom` profile.
+const KNOWN_PROFILES: [(&str, TupleLayout); 1] = [("BS510", TupleLayout::Single)];
+
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+impl DeviceProfile {
+    /// Looks up a built-in profile by name. Case and separators are ignored,
+    /// i.e. `bs-510` finds the `BS510` profile.
+    pub fn named(name: &str) -> Option<DeviceProfile> {
+        let normalized = normalize_name(name);
+        KNOWN_PROFILES
+            .iter()
+            .find(|(known, _)| normalize_name(known) == normalized)
+            .map(|(known, tuple_layout)| DeviceProfile {
+                name: known.to_string(),
+                vid: AREXX_VID,
+                pid: AREXX_PID,
+                read_endpoint: None,
+                write_endpoint: None,
+                init_sequence: vec![InitCommand::SetClock],
+                tuple_layout: *tuple_layout,
+            })
+    }
+
+    pub fn known_names() -> Vec<&'static str> {
+        KNOWN_PROFILES.iter().map(|(name, _)| *name).collect()
+    }
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        DeviceProfile::named("BS510").unwrap()
+    }
+}
+
+impl Display for DeviceProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (vid = 0x{:04x}, pid = 0x{:04x}, tuples = {:?})", self.name, self.vid, self.pid, self.tuple_layout)
+    }
+}
+
+/// The `[device]` section of the configuration file.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeviceConfig {
+    /// Name of a built-in profile or `custom`.
+    pub profile: Option<String>,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub read_endpoint: Option<u8>,
+    pub write_endpoint: Option<u8>,
+    pub init: Option<Vec<InitCommand>>,
+    pub tuple_layout: Option<TupleLayout>,
+    /// Name of the base station, used in sink templates (default: profile name).
+    pub station: Option<String>,
+}
+
+impl DeviceConfig {
+    /// Resolves the profile. The settings of the section override the ones of
+    /// the selected built-in profile, a `custom` profile requires `vid` and `pid`.
+    pub fn resolve(&self) -> Result<DeviceProfile, ConfigError> {
+        let profile_name = self.profile.as_deref().unwrap_or("BS510");
+        let mut profile = if profile_name.eq_ignore_ascii_case(CUSTOM_PROFILE) {
+            match (self.vid, self.pid) {
+                (Some(vid), Some(pid)) => DeviceProfile {
+                    name: CUSTOM_PROFILE.to_string(),
+                    vid,
+                    pid,
+                    read_endpoint: None,
+                    write_endpoint: None,
+                    init_sequence: vec![InitCommand::SetClock],
+                    tuple_layout: TupleLayout::Single,
+                },
+                _ => return Err(ConfigError::Invalid("device profile `custom` requires `vid` and `pid`".into())),
+            }
+        } else {
+            DeviceProfile::named(profile_name).ok_or_else(|| {
+                ConfigError::Invalid(format!(
+                    "unknown device profile `{}` (known: {}, {})",
+                    profile_name,
+                    DeviceProfile::known_names().join(", "),
+                    CUSTOM_PROFILE
+                ))
+            })?
+        };
+
+        if let Some(vid) = self.vid {
+            profile.vid = vid;
+        }
+        if let Some(pid) = self.pid {
+            profile.pid = pid;
+        }
+        if self.read_endpoint.is_some() {
+            profile.read_endpoint = self.read_endpoint;
+        }
+        if self.write_endpoint.is_some() {
+            profile.write_endpoint = self.write_endpoint;
+        }
+        if let Some(init) = &self.init {
+            profile.init_sequence = init.clone();
+        }
+        if let Some(tuple_layout) = self.tuple_layout {
+            profile.tuple_layout = tuple_layout;
+        }
+
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(toml: &str) -> DeviceConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn resolves_built_in_profile_with_overrides() {
+        let profile = device("profile = \"bs-510\"\nread-endpoint = 0x81").resolve().unwrap();
+        assert_eq!(profile.name, "BS510");
+        assert_eq!((profile.vid, profile.pid), (AREXX_VID, AREXX_PID));
+        assert_eq!(profile.read_endpoint, Some(0x81));
+        assert_eq!(profile.tuple_layout, TupleLayout::Single);
+    }
+
+    #[test]
+    fn resolves_custom_profile() {
+        let custom = device("profile = \"custom\"\nvid = 0x0451\npid = 0x3212\ntuple-layout = \"sequence\"");
+        let profile = custom.resolve().unwrap();
+        assert_eq!(profile.name, CUSTOM_PROFILE);
+        assert_eq!(profile.pid, 0x3212);
+        assert_eq!(profile.tuple_layout, TupleLayout::Sequence);
+        assert!(device("profile = \"custom\"\nvid = 0x0451").resolve().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_profiles() {
+        assert!(device("profile = \"TL-500\"").resolve().is_err());
+    }
+}
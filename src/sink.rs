@@ -1,30 +1,470 @@
 use crate::arexx::TemperatureReading;
+use crate::error::SinkError;
+use crate::spool::Spool;
+use async_trait::async_trait;
 use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
 
 mod data_file;
+mod filter;
 mod influxdb;
 mod mqtt;
+mod registry;
+mod worker;
 
-pub use crate::sink::data_file::DataFileSink;
-pub use crate::sink::influxdb::InfluxDbSink;
-pub use crate::sink::mqtt::MqttSink;
+pub use crate::sink::data_file::{DataFileError, DataFileSink, DataFileSinkFactory};
+pub use crate::sink::filter::SinkFilter;
+pub use crate::sink::influxdb::{line_protocol, InfluxDbError, InfluxDbSink, InfluxDbSinkFactory};
+pub use crate::sink::mqtt::{MqttError, MqttSink, MqttSinkFactory};
+pub use crate::sink::registry::{SinkFactory, SinkFailure, SinkRegistry};
+pub use crate::sink::worker::{SinkStatus, SinkWorker};
 
-pub enum SinkType {
-    DataFile(Box<DataFileSink>),
-    InfluxDb(Box<InfluxDbSink>),
-    Mqtt(Box<MqttSink>),
+/// Health of a sink, e.g. whether the connection to the server is up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkHealth {
+    Healthy,
+    Unhealthy(String),
 }
 
-impl fmt::Display for SinkType {
+/// A storage sink of the readings. Sinks are created by a [`SinkFactory`]
+/// of the [`SinkRegistry`] and driven by a [`SinkWorker`], which calls
+/// `start` once, `publish` for every reading and `flush` and `shutdown`
+/// when the application stops.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Prepares the sink before the first reading, e.g. connects to a server.
+    async fn start(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    async fn publish(&self, reading: &TemperatureReading) -> Result<(), SinkError>;
+
+    /// Writes buffered readings, called on shutdown.
+    async fn flush(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Removes the buffered readings which were not written yet and returns
+    /// them, oldest first, so that they can be spooled after a failed write.
+    async fn take_unflushed(&self) -> Vec<TemperatureReading> {
+        Vec::new()
+    }
+
+    /// Releases the resources of the sink after the last flush.
+    async fn shutdown(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn health(&self) -> SinkHealth {
+        SinkHealth::Healthy
+    }
+}
+
+/// Maximum number of spooled readings replayed at once, so that new
+/// readings are spooled in between.
+const MAX_REPLAY: usize = 100;
+/// Delay of the first replay after a failure, doubled after every failed
+/// replay up to [`REPLAY_MAX_DELAY`].
+const REPLAY_MIN_DELAY: Duration = Duration::from_secs(1);
+const REPLAY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A sink with an optional on-disk queue. Readings which fail with a
+/// retryable error or time out are spooled, together with the readings
+/// buffered in the sink, and replayed in order once the sink recovers.
+/// Replayed readings are removed from the spool once they are written, so a
+/// failure while replaying may write a reading twice but never loses it.
+pub struct SpooledSink {
+    name: String,
+    sink: Box<dyn Sink>,
+    spool: Option<Spool>,
+    timeout: Duration,
+    filter: SinkFilter,
+    replay_delay: Duration,
+    next_replay: Instant,
+}
+
+impl SpooledSink {
+    pub fn new(name: String, sink: Box<dyn Sink>, spool: Option<Spool>, timeout: Duration, filter: SinkFilter) -> Self {
+        SpooledSink {
+            name,
+            sink,
+            spool,
+            timeout,
+            filter,
+            replay_delay: REPLAY_MIN_DELAY,
+            next_replay: Instant::now(),
+        }
+    }
+
+    /// Readings which are published to the sink.
+    pub fn filter(&self) -> &SinkFilter {
+        &self.filter
+    }
+
+    pub fn health(&self) -> SinkHealth {
+        self.sink.health()
+    }
+
+    /// Number of readings waiting in the spool.
+    pub fn queue_depth(&self) -> usize {
+        self.spool.as_ref().map_or(0, Spool::depth)
+    }
+
+    /// Size of the spool on disk in bytes.
+    pub fn queue_size(&self) -> u64 {
+        self.spool.as_ref().map_or(0, Spool::size)
+    }
+
+    /// Time of the next replay of the spool, `None` while nothing is spooled.
+    pub fn next_replay(&self) -> Option<Instant> {
+        (self.queue_depth() > 0).then_some(self.next_replay)
+    }
+
+    /// Publishes a reading. While readings are spooled, the reading is queued
+    /// behind them, the spool is replayed by [`SpooledSink::replay`].
+    pub async fn publish(&mut self, reading: &TemperatureReading) -> Result<(), SinkError> {
+        let queued = match &self.spool {
+            None => return self.publish_with_timeout(reading).await,
+            Some(spool) => !spool.is_empty(),
+        };
+        if queued {
+            self.enqueue(reading);
+            return Ok(());
+        }
+        match self.publish_with_timeout(reading).await {
+            Err(error) if error.is_retryable() => {
+                tracing::warn!("{} unavailable, spooling readings", self.name);
+                self.enqueue_unflushed().await;
+                self.enqueue(reading);
+                self.replay_delay = REPLAY_MIN_DELAY;
+                self.next_replay = Instant::now() + self.replay_delay;
+                Err(error)
+            }
+            result => result,
+        }
+    }
+
+    /// Replays spooled readings. After a failure the next replay is delayed
+    /// with exponential backoff, otherwise it is due right away until the
+    /// spool is empty.
+    pub async fn replay(&mut self) -> Result<(), SinkError> {
+        let result = self.replay_batch().await;
+        match &result {
+            Ok(()) => {
+                self.replay_delay = REPLAY_MIN_DELAY;
+                self.next_replay = Instant::now();
+            }
+            Err(_) => {
+                self.next_replay = Instant::now() + self.replay_delay;
+                self.replay_delay = (self.replay_delay * 2).min(REPLAY_MAX_DELAY);
+            }
+        }
+        result
+    }
+
+    pub async fn start(&self) -> Result<(), SinkError> {
+        with_timeout(self.timeout, self.sink.start()).await
+    }
+
+    /// Writes the buffered readings, the readings which could not be written
+    /// are spooled.
+    pub async fn flush(&mut self) -> Result<(), SinkError> {
+        let result = with_timeout(self.timeout, self.sink.flush()).await;
+        if result.is_err() {
+            self.enqueue_unflushed().await;
+        }
+        result
+    }
+
+    pub async fn shutdown(&self) -> Result<(), SinkError> {
+        with_timeout(self.timeout, self.sink.shutdown()).await
+    }
+
+    async fn publish_with_timeout(&self, reading: &TemperatureReading) -> Result<(), SinkError> {
+        with_timeout(self.timeout, self.sink.publish(reading)).await
+    }
+
+    /// Moves the readings buffered in the sink to the spool.
+    async fn enqueue_unflushed(&mut self) {
+        if self.spool.is_none() {
+            return;
+        }
+        for reading in self.sink.take_unflushed().await {
+            self.enqueue(&reading);
+        }
+    }
+
+    fn enqueue(&mut self, reading: &TemperatureReading) {
+        if let Some(spool) = &mut self.spool {
+            match spool.push(reading) {
+                Ok(()) => tracing::debug!("spooled {} for {}, {} queued", reading, self.name, spool.depth()),
+                Err(error) => tracing::error!("error spooling {} for {}: {}", reading, self.name, error),
+            }
+        }
+    }
+
+    /// Replays up to [`MAX_REPLAY`] spooled readings and flushes the sink.
+    /// Only the readings which were written are removed from the spool.
+    async fn replay_batch(&mut self) -> Result<(), SinkError> {
+        let Some(spool) = &mut self.spool else {
+            return Ok(());
+        };
+        // spool positions after the readings passed to the sink
+        let mut published = Vec::new();
+        let mut result = Ok(());
+        while published.len() < MAX_REPLAY {
+            let reading = match spool.next_reading() {
+                Ok(Some(reading)) => reading,
+                Ok(None) => break,
+                Err(error) => {
+                    tracing::error!("error reading spool of {}: {}", self.name, error);
+                    break;
+                }
+            };
+            match with_timeout(self.timeout, self.sink.publish(&reading)).await {
+                Ok(()) => published.push(spool.position()),
+                Err(error) if error.is_retryable() => {
+                    result = Err(error);
+                    break;
+                }
+                Err(error) => tracing::error!("dropped spooled {} for {}: {}", reading, self.name, error),
+            }
+        }
+        if result.is_ok() {
+            result = with_timeout(self.timeout, self.sink.flush()).await;
+        }
+
+        let committed = match &result {
+            Ok(()) => spool.commit(spool.position()),
+            Err(_) => {
+                // the readings still buffered in the sink were not written
+                // and are replayed again
+                let unflushed = self.sink.take_unflushed().await.len();
+                let written = published.len().saturating_sub(unflushed);
+                let committed = match written {
+                    0 => Ok(()),
+                    written => spool.commit(published[written - 1]),
+                };
+                spool.rewind();
+                committed
+            }
+        };
+        if let Err(error) = committed {
+            tracing::error!("error updating spool of {}: {}", self.name, error);
+        } else if spool.is_empty() {
+            tracing::info!("{} recovered, spool replayed", self.name);
+        }
+        result
+    }
+}
+
+/// Runs a sink operation, failing with [`SinkError::Timeout`] if it takes
+/// longer than `timeout`.
+async fn with_timeout(timeout: Duration, operation: impl Future<Output = Result<(), SinkError>>) -> Result<(), SinkError> {
+    tokio::time::timeout(timeout, operation)
+        .await
+        .unwrap_or(Err(SinkError::Timeout(timeout)))
+}
+
+impl fmt::Display for SpooledSink {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            SinkType::DataFile(_) => write!(f, "DateFile"),
-            SinkType::InfluxDb(_) => write!(f, "InfluxDB"),
-            SinkType::Mqtt(_) => write!(f, "MQTT"),
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Converts a sensor name into a lowercase topic level, e.g. `Living room`
+/// into `living-room`. Characters other than ASCII letters and digits are
+/// replaced by `-`.
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
         }
     }
+    slug.trim_end_matches('-').to_string()
 }
 
-pub trait Sink {
-    async fn publish(&self, reading: &TemperatureReading) -> anyhow::Result<()>;
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::config::SpoolConfig;
+
+    /// Sink which writes batches of two readings while it has writes left.
+    #[derive(Default)]
+    struct BatchingSink {
+        writes_left: Arc<AtomicUsize>,
+        buffer: Mutex<Vec<TemperatureReading>>,
+        written: Arc<Mutex<Vec<f32>>>,
+    }
+
+    impl BatchingSink {
+        fn write(&self, buffer: &mut Vec<TemperatureReading>) -> Result<(), SinkError> {
+            if self.writes_left.load(Ordering::Relaxed) == 0 {
+                return Err(SinkError::Other {
+                    source: "unavailable".into(),
+                    retryable: true,
+                });
+            }
+            self.writes_left.fetch_sub(1, Ordering::Relaxed);
+            self.written.lock().unwrap().extend(buffer.drain(..).map(|reading| reading.value));
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Sink for BatchingSink {
+        async fn publish(&self, reading: &TemperatureReading) -> Result<(), SinkError> {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.push(reading.clone());
+            if buffer.len() < 2 {
+                return Ok(());
+            }
+            let result = self.write(&mut buffer);
+            if result.is_err() {
+                buffer.pop();
+            }
+            result
+        }
+
+        async fn flush(&self) -> Result<(), SinkError> {
+            let mut buffer = self.buffer.lock().unwrap();
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            self.write(&mut buffer)
+        }
+
+        async fn take_unflushed(&self) -> Vec<TemperatureReading> {
+            self.buffer.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    fn spool_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("arexx-tap-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn spooled_sink(directory: PathBuf) -> (SpooledSink, Arc<AtomicUsize>, Arc<Mutex<Vec<f32>>>) {
+        let sink = BatchingSink::default();
+        let writes_left = sink.writes_left.clone();
+        let written = sink.written.clone();
+        let config = SpoolConfig {
+            enabled: true,
+            directory: String::new(),
+            max_size_mb: None,
+            max_age_hours: None,
+        };
+        let spool = Spool::open(directory, &config).unwrap();
+        let sink = SpooledSink::new(
+            "test".into(),
+            Box::new(sink),
+            Some(spool),
+            Duration::from_secs(1),
+            SinkFilter::default(),
+        );
+        (sink, writes_left, written)
+    }
+
+    fn reading(value: f32) -> TemperatureReading {
+        TemperatureReading {
+            timestamp: Utc::now().fixed_offset(),
+            sensor: 1111,
+            name: "Kitchen".into(),
+            kind: "temperature".into(),
+            station: "BS510".into(),
+            tags: Default::default(),
+            value,
+            raw_value: None,
+            scaling: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn spools_buffered_readings_and_replays_them_in_order() {
+        let directory = spool_directory("replay");
+        let (mut sink, writes_left, written) = spooled_sink(directory.clone());
+
+        assert!(sink.publish(&reading(1.0)).await.is_ok());
+        assert!(sink.publish(&reading(2.0)).await.is_err());
+        assert_eq!(sink.queue_depth(), 2);
+        // queued behind the spooled readings without trying the sink
+        writes_left.store(2, Ordering::Relaxed);
+        assert!(sink.publish(&reading(3.0)).await.is_ok());
+        assert!(sink.publish(&reading(4.0)).await.is_ok());
+        assert_eq!(sink.queue_depth(), 4);
+        assert!(written.lock().unwrap().is_empty());
+
+        assert!(sink.replay().await.is_ok());
+        assert_eq!(sink.queue_depth(), 0);
+        assert_eq!(sink.next_replay(), None);
+        assert_eq!(*written.lock().unwrap(), vec![1.0, 2.0, 3.0, 4.0]);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn keeps_replayed_readings_until_written() {
+        let directory = spool_directory("partial-replay");
+        let (mut sink, writes_left, written) = spooled_sink(directory.clone());
+
+        for value in [1.0, 2.0, 3.0, 4.0] {
+            let _ = sink.publish(&reading(value)).await;
+        }
+        assert_eq!(sink.queue_depth(), 4);
+
+        // 1 and 2 are written, 3 stays buffered when 4 fails
+        writes_left.store(1, Ordering::Relaxed);
+        assert!(sink.replay().await.is_err());
+        assert_eq!(sink.queue_depth(), 2);
+        assert_eq!(*written.lock().unwrap(), vec![1.0, 2.0]);
+
+        // the spool is still there after a restart
+        drop(sink);
+        let (mut sink, writes_left, written) = spooled_sink(directory.clone());
+        assert_eq!(sink.queue_depth(), 2);
+        writes_left.store(2, Ordering::Relaxed);
+        assert!(sink.publish(&reading(5.0)).await.is_ok());
+        assert!(sink.replay().await.is_ok());
+        assert_eq!(sink.queue_depth(), 0);
+        assert_eq!(*written.lock().unwrap(), vec![3.0, 4.0, 5.0]);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn delays_replay_after_failure() {
+        let directory = spool_directory("backoff");
+        let (mut sink, _, _) = spooled_sink(directory.clone());
+
+        let _ = sink.publish(&reading(1.0)).await;
+        let _ = sink.publish(&reading(2.0)).await;
+        let first = sink.next_replay().unwrap();
+        assert!(first > Instant::now());
+        assert!(sink.replay().await.is_err());
+        assert!(sink.replay().await.is_err());
+        let delay = sink.next_replay().unwrap() - Instant::now();
+        assert!(delay > REPLAY_MIN_DELAY && delay <= REPLAY_MIN_DELAY * 2, "{:?}", delay);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn spools_unflushed_readings_on_failed_flush() {
+        let directory = spool_directory("flush");
+        let (mut sink, _, _) = spooled_sink(directory.clone());
+
+        assert!(sink.publish(&reading(1.0)).await.is_ok());
+        assert!(sink.flush().await.is_err());
+        assert_eq!(sink.queue_depth(), 1);
+        let _ = std::fs::remove_dir_all(directory);
+    }
 }
@@ -5,29 +5,47 @@ use std::{
 };
 
 use crate::arexx::TemperatureReading;
-use crate::config::DataFileConfig;
-use anyhow::{Context, Ok, Result};
+use crate::config::{ConfigFile, DataFileConfig, SinkConfig};
+use crate::error::{ConfigError, SinkError};
+use async_trait::async_trait;
+use thiserror::Error;
 
-use super::Sink;
+use super::{Sink, SinkFactory};
+
+#[derive(Debug, Error)]
+pub enum DataFileError {
+    #[error("can't open file {path}: {source}")]
+    Open {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("cannot write to file: {0}")]
+    Write(#[from] std::io::Error),
+    #[error("json serialization failed: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl DataFileError {
+    /// Write errors (e.g. a full disk) may resolve themselves, a file which
+    /// cannot be opened or a reading which cannot be serialized won't.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, DataFileError::Write(_))
+    }
+}
 
 pub struct DataFileSink {
     file: File,
 }
 
 impl DataFileSink {
-    pub fn new(config: &DataFileConfig) -> Result<Option<Self>> {
-        if config.enabled {
-            let path = &config.file;
-            let file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path)
-                .with_context(|| format!("Can't open file {}", path))
-                .unwrap();
-            Ok(Some(DataFileSink { file }))
-        } else {
-            Ok(None)
-        }
+    pub fn new(config: &DataFileConfig) -> Result<Self, DataFileError> {
+        let path = &config.file;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| DataFileError::Open { path: path.to_owned(), source })?;
+        Ok(DataFileSink { file })
     }
 }
 
@@ -37,18 +55,32 @@ impl Display for DataFileSink {
     }
 }
 
+#[async_trait]
 impl Sink for DataFileSink {
-    async fn publish(&self, reading: &TemperatureReading) -> Result<()> {
+    async fn publish(&self, reading: &TemperatureReading) -> Result<(), SinkError> {
         tracing::trace!("publish DataFile {}", reading);
-        let temperature_json = serde_json::to_string(&reading)
-            .context("Json serialization failed")
-            .unwrap();
+        let temperature_json = serde_json::to_string(&reading).map_err(DataFileError::from)?;
         let mut f = &self.file;
-        writeln!(f, "{}", &temperature_json)
-            .context("cannot write to file")
-            .unwrap();
-        f.flush().context("flush failed").unwrap();
+        writeln!(f, "{}", &temperature_json).map_err(DataFileError::from)?;
+        f.flush().map_err(DataFileError::from)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Creates JSON lines file sinks, `type = "DataFile"`.
+pub struct DataFileSinkFactory;
+
+impl SinkFactory for DataFileSinkFactory {
+    fn type_name(&self) -> &str {
+        "DataFile"
+    }
+
+    fn validate(&self, config: &SinkConfig, _config_file: &ConfigFile) -> Result<(), ConfigError> {
+        config.parse::<DataFileConfig>().map(|_| ())
+    }
+
+    fn create(&self, config: &SinkConfig, _config_file: &ConfigFile) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(DataFileSink::new(&config.parse()?)?))
+    }
+}
@@ -0,0 +1,110 @@
+//! Rules which select the readings a sink receives.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::arexx::TemperatureReading;
+use crate::error::ConfigError;
+
+/// The `filter` table of a `[[sink]]` entry. A reading is published to the
+/// sink if it matches all configured rules, an empty filter matches every
+/// reading. Unknown keys are rejected, e.g. sink settings which follow the
+/// `[sink.filter]` table by mistake.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SinkFilter {
+    /// Sensor IDs which are published, all sensors if not set.
+    pub sensors: Option<Vec<u16>>,
+    /// Sensor IDs which are never published.
+    #[serde(rename = "exclude-sensors", default)]
+    pub exclude_sensors: Vec<u16>,
+    /// Sensor tags which must have the given values.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(rename = "min-value")]
+    pub min_value: Option<f32>,
+    #[serde(rename = "max-value")]
+    pub max_value: Option<f32>,
+}
+
+impl SinkFilter {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let (Some(min_value), Some(max_value)) = (self.min_value, self.max_value) {
+            if min_value > max_value {
+                return Err(ConfigError::Invalid(format!(
+                    "filter: `min-value` {} is greater than `max-value` {}",
+                    min_value, max_value
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, reading: &TemperatureReading) -> bool {
+        self.sensors.as_ref().is_none_or(|sensors| sensors.contains(&reading.sensor))
+            && !self.exclude_sensors.contains(&reading.sensor)
+            && self.tags.iter().all(|(tag, value)| reading.tags.get(tag) == Some(value))
+            && self.min_value.is_none_or(|min_value| reading.value >= min_value)
+            && self.max_value.is_none_or(|max_value| reading.value <= max_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn filter(toml: &str) -> SinkFilter {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn reading(sensor: u16, location: &str, value: f32) -> TemperatureReading {
+        TemperatureReading {
+            timestamp: Utc::now().fixed_offset(),
+            sensor,
+            name: "Garden".into(),
+            kind: "temperature".into(),
+            station: "BS510".into(),
+            tags: [("location".to_string(), location.to_string())].into(),
+            value,
+            raw_value: None,
+            scaling: None,
+        }
+    }
+
+    #[test]
+    fn empty_filter_matches_every_reading() {
+        assert!(filter("").matches(&reading(1111, "outdoor", 21.5)));
+    }
+
+    #[test]
+    fn matches_sensor_lists() {
+        let filter = filter("sensors = [1111, 2222]\nexclude-sensors = [2222]");
+        assert!(filter.matches(&reading(1111, "outdoor", 21.5)));
+        assert!(!filter.matches(&reading(2222, "outdoor", 21.5)));
+        assert!(!filter.matches(&reading(3333, "outdoor", 21.5)));
+    }
+
+    #[test]
+    fn matches_tags_and_inclusive_value_range() {
+        let filter = filter("tags = { location = \"outdoor\" }\nmin-value = -40.0\nmax-value = 60.0");
+        assert!(filter.matches(&reading(1111, "outdoor", -40.0)));
+        assert!(filter.matches(&reading(1111, "outdoor", 60.0)));
+        assert!(!filter.matches(&reading(1111, "outdoor", 60.5)));
+        assert!(!filter.matches(&reading(1111, "indoor", 21.5)));
+    }
+
+    #[test]
+    fn rejects_inverted_value_range() {
+        assert!(filter("min-value = 10.0\nmax-value = 0.0").validate().is_err());
+        assert!(filter("min-value = 0.0\nmax-value = 0.0").validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        // a sink setting after the `[sink.filter]` table
+        assert!(toml::from_str::<SinkFilter>("sensors = [1111]\nqos = 1").is_err());
+    }
+}
@@ -1,22 +1,95 @@
 use crate::arexx::TemperatureReading;
-use crate::config::InfluxDbConfig;
-use crate::sink::Sink;
-use anyhow::{Context, Ok, Result};
-use chrono::{DateTime, Utc};
-use influxdb::{Client, InfluxDbWriteable, ReadQuery, Timestamp};
-use serde::{Deserialize, Serialize};
+use crate::config::{ConfigFile, InfluxDbApi, InfluxDbConfig, InfluxDbPrecision, InfluxDbSchema, SinkConfig};
+use crate::error::{ConfigError, SinkError};
+use crate::sink::{Sink, SinkFactory};
+use crate::template::Template;
+use async_trait::async_trait;
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::JoinHandle;
 
-#[derive(InfluxDbWriteable, Serialize, Deserialize, Debug, Clone, PartialEq)]
-struct InfluxDbTemperatureReading {
-    time: DateTime<Utc>,
-    temperature: f32,
+use self::batch::Batcher;
+use self::line_protocol::{FieldValue, Point};
+use self::writer::InfluxDbWriter;
+
+mod batch;
+pub mod line_protocol;
+mod writer;
+
+const DEFAULT_BATCH_DELAY_MS: u64 = 5000;
+const DEFAULT_MAX_BUFFERED: usize = 10000;
+
+#[derive(Debug, Error)]
+pub enum InfluxDbError {
+    #[error("failed to create HTTP client: {0}")]
+    Client(reqwest::Error),
+    #[error("invalid InfluxDB URL `{url}`: {message}")]
+    InvalidUrl { url: String, message: String },
+    #[error("failed to load CA certificate {path}: {message}")]
+    Certificate { path: String, message: String },
+    #[error("cannot connect to InfluxDB: {0}")]
+    Connect(reqwest::Error),
+    #[error("failed writing temperature record: {0}")]
+    Connection(reqwest::Error),
+    #[error("InfluxDB rejected credentials: HTTP {status}: {message}")]
+    Unauthorized { status: u16, message: String },
+    #[error("InfluxDB rejected write: HTTP {status}: {message}")]
+    BadRequest { status: u16, message: String },
+    #[error("InfluxDB bucket or database not found: {message}")]
+    NotFound { message: String },
+    #[error("InfluxDB unavailable: HTTP {status}: {message}")]
+    Unavailable {
+        status: u16,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("InfluxDB server error: HTTP {status}: {message}")]
+    Server { status: u16, message: String },
+    #[error("InfluxDB write buffer full ({0} points)")]
+    BufferFull(usize),
+}
+
+impl InfluxDbError {
+    /// Classifies a failed write by its HTTP status code.
+    fn from_status(status: u16, message: String, retry_after: Option<Duration>) -> InfluxDbError {
+        match status {
+            401 | 403 => InfluxDbError::Unauthorized { status, message },
+            404 => InfluxDbError::NotFound { message },
+            429 | 503 => InfluxDbError::Unavailable { status, message, retry_after },
+            500.. => InfluxDbError::Server { status, message },
+            _ => InfluxDbError::BadRequest { status, message },
+        }
+    }
+
+    /// Connection errors, server side errors and a full write buffer are
+    /// retryable, rejected credentials or writes are not.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            InfluxDbError::Client(_)
+            | InfluxDbError::InvalidUrl { .. }
+            | InfluxDbError::Certificate { .. }
+            | InfluxDbError::Unauthorized { .. }
+            | InfluxDbError::BadRequest { .. }
+            | InfluxDbError::NotFound { .. } => false,
+            InfluxDbError::Connect(_)
+            | InfluxDbError::Connection(_)
+            | InfluxDbError::Unavailable { .. }
+            | InfluxDbError::Server { .. }
+            | InfluxDbError::BufferFull(_) => true,
+        }
+    }
 }
 
 pub struct InfluxDbSink {
     url: String,
-    client: Client,
+    batcher: Arc<Batcher>,
+    flush_timer: Option<JoinHandle<()>>,
+    precision: InfluxDbPrecision,
+    schema: InfluxDbSchema,
     measurement_base: String,
+    measurement: Option<Template>,
 }
 
 impl Display for InfluxDbSink {
@@ -25,63 +98,196 @@ impl Display for InfluxDbSink {
     }
 }
 
-impl InfluxDbSink {
-    pub fn new(config: &InfluxDbConfig) -> Result<Option<Self>> {
-        if config.enabled {
-            let client = Client::new(&config.url, &config.bucket).with_token(&config.token);
-            Ok(Some(InfluxDbSink {
-                client,
-                measurement_base: config.measurement_base.to_owned(),
-                url: config.url.to_string(),
-            }))
-        } else {
-            Ok(None)
+impl Drop for InfluxDbSink {
+    fn drop(&mut self) {
+        if let Some(flush_timer) = &self.flush_timer {
+            flush_timer.abort();
         }
     }
+}
 
-    fn format_measurement_name(&self, sensor: u16) -> String {
-        format!("{}.{}", &self.measurement_base, sensor)
+impl InfluxDbSink {
+    /// Creates the sink, failed writes are retried within `timeout`, the
+    /// publish timeout of the sink.
+    pub fn new(config: &InfluxDbConfig, timeout: Duration) -> Result<Self, InfluxDbError> {
+        let batch_size = config.batch_size.unwrap_or(1);
+        let batcher = Arc::new(Batcher::new(
+            InfluxDbWriter::new(config, timeout)?,
+            batch_size,
+            Duration::from_millis(config.batch_delay_ms.unwrap_or(DEFAULT_BATCH_DELAY_MS)),
+            config.max_buffered.unwrap_or(DEFAULT_MAX_BUFFERED),
+        ));
+        let flush_timer = (batch_size > 1).then(|| Batcher::spawn_flush_timer(batcher.clone()));
+
+        Ok(InfluxDbSink {
+            batcher,
+            flush_timer,
+            precision: config.precision,
+            schema: config.schema,
+            measurement_base: config.measurement_base.clone().unwrap_or_default(),
+            measurement: config.measurement.clone(),
+            url: config.url.to_string(),
+        })
     }
 
-    // currently not used
-    #[allow(dead_code)]
-    pub async fn last_insert_time(&self) -> Result<Option<DateTime<Utc>>> {
-        // https://docs.influxdata.com/influxdb/v1/query_language/explore-data/
-        // SELECT count(*) FROM /^mqtt.0.smartmeter.61064149.*/
-        // SELECT * FROM /^mqtt\.0\.smartmeter\.61064149.*/ ORDER BY time DESC LIMIT 1
+    fn to_point(&self, reading: &TemperatureReading) -> Point {
+        let mut point = Point::new(self.format_measurement_name(reading), reading.timestamp)
+            .add_field("value", FieldValue::Float(reading.value))
+            .add_tag("name", reading.name.clone());
+        if self.schema == InfluxDbSchema::Tagged {
+            point = point
+                .add_tag("sensor_id", reading.sensor.to_string())
+                .add_tag("station", reading.station.clone());
+            if let Some(raw_value) = reading.raw_value {
+                point = point.add_field("raw_value", FieldValue::Integer(raw_value.into()));
+            }
+            if let Some(scaling) = reading.scaling {
+                point = point.add_field("scaling", FieldValue::Float(scaling));
+            }
+        }
+        reading.tags.iter().fold(point, |point, (tag, value)| point.add_tag(tag, value.clone()))
+    }
 
-        let all_topic_regex = format!("^{}.*", self.measurement_base);
-        let read_query = ReadQuery::new(format!(
-            "SELECT * FROM /{}/ ORDER BY time DESC LIMIT 1",
-            all_topic_regex
-        ));
-        let read_result = self
-            .client
-            .json_query(read_query)
-            .await
-            .and_then(|mut db_result| db_result.deserialize_next::<InfluxDbTemperatureReading>())
-            .context("failed to execute InfluxDB query")
-            .unwrap();
-        if !read_result.series.is_empty() {
-            let temperature_reading = &read_result.series[0].values[0];
-            Ok(Some(temperature_reading.time))
-        } else {
-            Ok(None)
+    fn format_measurement_name(&self, reading: &TemperatureReading) -> String {
+        match (&self.measurement, self.schema) {
+            (Some(template), _) => template.render(reading, str::to_string),
+            (None, InfluxDbSchema::Legacy) => format!("{}.{}", &self.measurement_base, reading.sensor),
+            (None, InfluxDbSchema::Tagged) => reading.kind.clone(),
         }
     }
 }
 
+#[async_trait]
 impl Sink for InfluxDbSink {
-    async fn publish(&self, reading: &TemperatureReading) -> Result<()> {
+    async fn publish(&self, reading: &TemperatureReading) -> Result<(), SinkError> {
         tracing::trace!("publish InfluxDB {}", reading);
-        let millis = reading.timestamp.to_utc().timestamp_millis() as u128;
-        let wq = self.format_measurement_name(reading.sensor);
-        let temperature_readings = Timestamp::Milliseconds(millis)
-            .into_query(wq)
-            .add_field("value", reading.value);
+        let line = self.to_point(reading).to_line(self.precision);
+        self.batcher.push(reading, line).await?;
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        self.batcher.flush().await?;
+        Ok(())
+    }
+
+    async fn take_unflushed(&self) -> Vec<TemperatureReading> {
+        self.batcher.take().await
+    }
+}
 
-        self.client.query(temperature_readings).await.expect("failed writing temperature record");
+/// Creates InfluxDB sinks, `type = "InfluxDB"`.
+pub struct InfluxDbSinkFactory;
 
+impl SinkFactory for InfluxDbSinkFactory {
+    fn type_name(&self) -> &str {
+        "InfluxDB"
+    }
+
+    fn validate(&self, config: &SinkConfig, config_file: &ConfigFile) -> Result<(), ConfigError> {
+        let config: InfluxDbConfig = config.parse()?;
+        if let Some(measurement) = &config.measurement {
+            measurement.validate(&config_file.sensors)?;
+        }
+        if config.api == InfluxDbApi::V2 && config.org.is_none() {
+            return Err(ConfigError::Invalid("InfluxDB: v2 API requires `org`".into()));
+        }
+        if config.api == InfluxDbApi::V2
+            && (config.username.is_some() || config.retention_policy.is_some() || config.consistency.is_some())
+        {
+            return Err(ConfigError::Invalid(
+                "InfluxDB: `username`, `retention-policy` and `consistency` are only supported by the v1 API".into(),
+            ));
+        }
+        if config.detect_start_time.is_some() {
+            return Err(ConfigError::Invalid(
+                "InfluxDB: `detect_start_time` is not supported, remove it from the configuration".into(),
+            ));
+        }
+        if config.username.is_some() != config.password.is_some() {
+            return Err(ConfigError::Invalid("InfluxDB: `username` and `password` must be set together".into()));
+        }
+        if config.schema == InfluxDbSchema::Legacy && config.measurement_base.is_none() && config.measurement.is_none() {
+            return Err(ConfigError::Invalid(
+                "InfluxDB: legacy schema requires `measurement-base` or `measurement`".into(),
+            ));
+        }
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn create(&self, config: &SinkConfig, _config_file: &ConfigFile) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(InfluxDbSink::new(&config.parse()?, config.options.timeout())?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    fn sink(schema: &str) -> InfluxDbSink {
+        let config: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"http://localhost:8086\"\nbucket = \"arexx\"\nmeasurement-base = \"mqtt.0.temp\"\nschema = \"{}\"\n",
+            schema
+        ))
+        .unwrap();
+        InfluxDbSink::new(&config, Duration::from_secs(30)).unwrap()
+    }
+
+    fn reading() -> TemperatureReading {
+        TemperatureReading {
+            timestamp: DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap(),
+            sensor: 1111,
+            name: "Living room".into(),
+            kind: "temperature".into(),
+            station: "BS510".into(),
+            tags: [("room".to_string(), "ground".to_string())].into(),
+            value: 21.5,
+            raw_value: Some(2750),
+            scaling: Some(0.0078),
+        }
+    }
+
+    #[test]
+    fn writes_legacy_schema() {
+        assert_eq!(
+            sink("legacy").to_point(&reading()).to_line(InfluxDbPrecision::S),
+            "mqtt.0.temp.1111,name=Living\\ room,room=ground value=21.5 1704110400"
+        );
+    }
+
+    #[test]
+    fn writes_tagged_schema_with_raw_value_and_scaling() {
+        assert_eq!(
+            sink("tagged").to_point(&reading()).to_line(InfluxDbPrecision::S),
+            "temperature,name=Living\\ room,room=ground,sensor_id=1111,station=BS510 \
+             value=21.5,raw_value=2750i,scaling=0.0078 1704110400"
+        );
+    }
+
+    #[test]
+    fn rejects_detect_start_time() {
+        let config: ConfigFile = toml::from_str(
+            "sensors = []\n[[sink]]\ntype = \"InfluxDB\"\nenabled = true\nurl = \"http://localhost:8086\"\n\
+             bucket = \"arexx\"\nmeasurement-base = \"mqtt.0.temp\"\ndetect_start_time = true\n",
+        )
+        .unwrap();
+        let result = InfluxDbSinkFactory.validate(&config.sink[0], &config);
+        assert!(matches!(result, Err(ConfigError::Invalid(message)) if message.contains("detect_start_time")));
+    }
+
+    #[test]
+    fn omits_raw_value_of_spooled_readings_without_it() {
+        let reading = TemperatureReading {
+            raw_value: None,
+            scaling: None,
+            ..reading()
+        };
+        assert_eq!(
+            sink("tagged").to_point(&reading).to_line(InfluxDbPrecision::S),
+            "temperature,name=Living\\ room,room=ground,sensor_id=1111,station=BS510 value=21.5 1704110400"
+        );
+    }
+}
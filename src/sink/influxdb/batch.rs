@@ -0,0 +1,328 @@
+//! Buffering of line protocol points which are written in batches.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tokio::task::JoinHandle;
+
+use crate::arexx::TemperatureReading;
+
+use super::writer::InfluxDbWriter;
+use super::InfluxDbError;
+
+/// A point in line protocol and the reading it was created from.
+struct BufferedPoint {
+    id: u64,
+    reading: TemperatureReading,
+    line: String,
+}
+
+struct Buffer {
+    points: VecDeque<BufferedPoint>,
+    /// Time the oldest buffered point was added.
+    oldest: Option<Instant>,
+    /// Number of points which are being written.
+    writing: usize,
+    next_id: u64,
+}
+
+impl Buffer {
+    fn remove(&mut self, id: u64) {
+        if let Some(position) = self.points.iter().rposition(|point| point.id == id) {
+            self.points.remove(position);
+        }
+        if self.points.is_empty() {
+            self.oldest = None;
+        }
+    }
+}
+
+/// Batch which was taken from the buffer to be written. Unless it was
+/// written or rejected, the batch is put back to the front of the buffer,
+/// also if the write is cancelled. The point `excluded` is not put back.
+struct TakenBatch<'a> {
+    batcher: &'a Batcher,
+    points: Vec<BufferedPoint>,
+    taken: usize,
+    excluded: Option<u64>,
+}
+
+impl Drop for TakenBatch<'_> {
+    fn drop(&mut self) {
+        let mut buffer = self.batcher.buffer();
+        buffer.writing -= self.taken;
+        let put_back = !self.points.is_empty();
+        for point in self.points.drain(..).rev() {
+            if Some(point.id) != self.excluded {
+                buffer.points.push_front(point);
+            }
+        }
+        if put_back && !buffer.points.is_empty() {
+            buffer.oldest = Some(Instant::now());
+        }
+    }
+}
+
+/// Point of a push which writes a batch. The point is removed from the
+/// buffer when the write fails with a retryable error or the push is
+/// cancelled, e.g. by the sink timeout, since the caller spools the reading.
+struct PendingPush<'a> {
+    batcher: &'a Batcher,
+    id: u64,
+    completed: bool,
+}
+
+impl Drop for PendingPush<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.batcher.buffer().remove(self.id);
+        }
+    }
+}
+
+/// Buffers points and writes them when `batch_size` points are buffered or
+/// the oldest point is older than `max_delay`.
+///
+/// The buffer is not locked while a batch is written, so pushes don't wait
+/// for a write of the flush timer. Points of a batch which failed with a
+/// retryable error are kept and sent again with the next flush, except for
+/// the point whose push triggered the write: the error is returned for it,
+/// so the caller can spool it. The same applies if the push is cancelled
+/// while writing. At most `max_buffered` points are kept, including the
+/// ones being written, a push to a full buffer fails with a retryable error.
+/// The readings of the points which were not written can be taken with
+/// [`Batcher::take`], e.g. to spool them after a failed flush.
+pub(super) struct Batcher {
+    writer: InfluxDbWriter,
+    buffer: Mutex<Buffer>,
+    batch_size: usize,
+    max_delay: Duration,
+    max_buffered: usize,
+}
+
+impl Batcher {
+    pub(super) fn new(writer: InfluxDbWriter, batch_size: usize, max_delay: Duration, max_buffered: usize) -> Self {
+        Batcher {
+            writer,
+            buffer: Mutex::new(Buffer {
+                points: VecDeque::new(),
+                oldest: None,
+                writing: 0,
+                next_id: 0,
+            }),
+            batch_size: batch_size.max(1),
+            max_delay,
+            max_buffered: max_buffered.max(batch_size),
+        }
+    }
+
+    fn buffer(&self) -> MutexGuard<'_, Buffer> {
+        self.buffer.lock().unwrap()
+    }
+
+    /// Adds a point and writes the buffered points once the batch is full.
+    /// The point is not kept if the write fails or the push is cancelled.
+    pub(super) async fn push(&self, reading: &TemperatureReading, line: String) -> Result<(), InfluxDbError> {
+        let (id, full) = {
+            let mut buffer = self.buffer();
+            if buffer.points.len() + buffer.writing >= self.max_buffered {
+                return Err(InfluxDbError::BufferFull(self.max_buffered));
+            }
+            let id = buffer.next_id;
+            buffer.next_id += 1;
+            buffer.points.push_back(BufferedPoint {
+                id,
+                reading: reading.clone(),
+                line,
+            });
+            buffer.oldest.get_or_insert_with(Instant::now);
+            (id, buffer.points.len() >= self.batch_size)
+        };
+
+        if full {
+            let mut pending = PendingPush {
+                batcher: self,
+                id,
+                completed: false,
+            };
+            let result = self.flush_buffer(Some(id)).await;
+            pending.completed = !result.as_ref().is_err_and(InfluxDbError::is_retryable);
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes all buffered points.
+    pub(super) async fn flush(&self) -> Result<(), InfluxDbError> {
+        self.flush_buffer(None).await
+    }
+
+    /// Removes the buffered points and returns their readings, oldest first.
+    pub(super) async fn take(&self) -> Vec<TemperatureReading> {
+        let mut buffer = self.buffer();
+        buffer.oldest = None;
+        buffer.points.drain(..).map(|point| point.reading).collect()
+    }
+
+    /// Writes the buffered points if the oldest one exceeds the maximum delay.
+    async fn flush_due(&self) -> Result<(), InfluxDbError> {
+        let due = self.buffer().oldest.is_some_and(|oldest| oldest.elapsed() >= self.max_delay);
+        if due {
+            self.flush_buffer(None).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes the buffered points in batches. A failed batch is put back,
+    /// except for the point `excluded`.
+    async fn flush_buffer(&self, excluded: Option<u64>) -> Result<(), InfluxDbError> {
+        loop {
+            let mut batch = {
+                let mut buffer = self.buffer();
+                let count = buffer.points.len().min(self.batch_size);
+                let points: Vec<BufferedPoint> = buffer.points.drain(..count).collect();
+                if buffer.points.is_empty() {
+                    buffer.oldest = None;
+                }
+                buffer.writing += count;
+                TakenBatch {
+                    batcher: self,
+                    points,
+                    taken: count,
+                    excluded,
+                }
+            };
+            if batch.points.is_empty() {
+                return Ok(());
+            }
+            let count = batch.points.len();
+            let lines: Vec<&str> = batch.points.iter().map(|point| point.line.as_str()).collect();
+            match self.writer.write(lines.join("\n")).await {
+                Ok(()) => {
+                    tracing::trace!("InfluxDB wrote batch of {} points", count);
+                    batch.points.clear();
+                }
+                Err(error) if error.is_retryable() => {
+                    tracing::debug!("InfluxDB batch of {} points failed, keeping the points", count);
+                    return Err(error);
+                }
+                Err(error) => {
+                    // the server rejected the batch, sending it again won't help
+                    tracing::error!("InfluxDB rejected batch, dropped {} points", count);
+                    batch.points.clear();
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    /// Periodically writes the points which exceed the maximum delay.
+    pub(super) fn spawn_flush_timer(batcher: Arc<Batcher>) -> JoinHandle<()> {
+        let period = (batcher.max_delay / 4).max(Duration::from_millis(100));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(error) = batcher.flush_due().await {
+                    tracing::error!("error flushing InfluxDB batch: {}", error);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::InfluxDbConfig;
+
+    fn reading(value: f32) -> TemperatureReading {
+        TemperatureReading {
+            timestamp: Utc::now().fixed_offset(),
+            sensor: 1111,
+            name: "Kitchen".into(),
+            kind: "temperature".into(),
+            station: "BS510".into(),
+            tags: Default::default(),
+            value,
+            raw_value: None,
+            scaling: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn removes_point_of_cancelled_push() {
+        // accepts the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"http://{}\"\nbucket = \"arexx\"\nmax-retries = 0\n",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let writer = InfluxDbWriter::new(&config, Duration::from_secs(30)).unwrap();
+        let batcher = Batcher::new(writer, 2, Duration::from_secs(60), 100);
+
+        batcher.push(&reading(1.0), "temperature value=1".into()).await.unwrap();
+        let cancelled = reading(2.0);
+        let push = batcher.push(&cancelled, "temperature value=2".into());
+        assert!(tokio::time::timeout(Duration::from_millis(200), push).await.is_err());
+
+        let unflushed: Vec<f32> = batcher.take().await.iter().map(|reading| reading.value).collect();
+        assert_eq!(unflushed, vec![1.0]);
+        drop(listener);
+    }
+
+    /// Batcher of a server which accepts connections but never answers.
+    async fn unanswered_batcher(batch_size: usize, max_buffered: usize) -> (Arc<Batcher>, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"http://{}\"\nbucket = \"arexx\"\nmax-retries = 0\n",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let writer = InfluxDbWriter::new(&config, Duration::from_secs(30)).unwrap();
+        (Arc::new(Batcher::new(writer, batch_size, Duration::from_secs(60), max_buffered)), listener)
+    }
+
+    #[tokio::test]
+    async fn pushes_while_a_batch_is_written() {
+        let (batcher, _listener) = unanswered_batcher(2, 100).await;
+        batcher.push(&reading(1.0), "temperature value=1".into()).await.unwrap();
+        let flush = tokio::spawn({
+            let batcher = batcher.clone();
+            async move { batcher.flush().await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let pushed = reading(2.0);
+        let push = batcher.push(&pushed, "temperature value=2".into());
+        assert!(tokio::time::timeout(Duration::from_millis(100), push).await.is_ok());
+
+        // the batch of the cancelled flush is put back in front
+        flush.abort();
+        let _ = flush.await;
+        let unflushed: Vec<f32> = batcher.take().await.iter().map(|reading| reading.value).collect();
+        assert_eq!(unflushed, vec![1.0, 2.0]);
+    }
+
+    #[tokio::test]
+    async fn rejects_push_to_full_buffer() {
+        let (batcher, _listener) = unanswered_batcher(2, 2).await;
+        batcher.push(&reading(1.0), "temperature value=1".into()).await.unwrap();
+        let write = tokio::spawn({
+            let batcher = batcher.clone();
+            async move { batcher.push(&reading(2.0), "temperature value=2".into()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let result = batcher.push(&reading(3.0), "temperature value=3".into()).await;
+        assert!(matches!(result, Err(InfluxDbError::BufferFull(2))), "{:?}", result);
+        write.abort();
+    }
+}
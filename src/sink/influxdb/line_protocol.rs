@@ -0,0 +1,143 @@
+//! Serialization of points in the InfluxDB line protocol.
+//!
+//! See <https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/>.
+
+use std::fmt::Write;
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::config::InfluxDbPrecision;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f32),
+    Integer(i64),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: DateTime<FixedOffset>,
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_measurement(value: &str) -> String {
+    escape(value, &[',', ' '])
+}
+
+fn escape_key(value: &str) -> String {
+    escape(value, &[',', '=', ' '])
+}
+
+impl Point {
+    pub fn new(measurement: String, timestamp: DateTime<FixedOffset>) -> Point {
+        Point {
+            measurement,
+            tags: Vec::new(),
+            fields: Vec::new(),
+            timestamp,
+        }
+    }
+
+    pub fn add_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Point {
+        self.tags.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn add_field(mut self, key: impl Into<String>, value: FieldValue) -> Point {
+        self.fields.push((key.into(), value));
+        self
+    }
+
+    fn timestamp(&self, precision: InfluxDbPrecision) -> i64 {
+        let utc = self.timestamp.to_utc();
+        match precision {
+            InfluxDbPrecision::Ns => utc.timestamp_nanos_opt().unwrap_or_default(),
+            InfluxDbPrecision::Us => utc.timestamp_micros(),
+            InfluxDbPrecision::Ms => utc.timestamp_millis(),
+            InfluxDbPrecision::S => utc.timestamp(),
+        }
+    }
+
+    /// Renders the point as a single line. Tags with empty values are
+    /// omitted, since InfluxDB rejects them.
+    pub fn to_line(&self, precision: InfluxDbPrecision) -> String {
+        let mut line = escape_measurement(&self.measurement);
+        let mut tags: Vec<&(String, String)> = self.tags.iter().filter(|(_, value)| !value.is_empty()).collect();
+        // sorted tags are recommended for write performance
+        tags.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, value) in tags {
+            let _ = write!(line, ",{}={}", escape_key(key), escape_key(value));
+        }
+        for (n, (key, value)) in self.fields.iter().enumerate() {
+            let separator = if n == 0 { ' ' } else { ',' };
+            let value = match value {
+                FieldValue::Float(value) => format!("{}", value),
+                FieldValue::Integer(value) => format!("{}i", value),
+                FieldValue::String(value) => format!("\"{}\"", escape(value, &['"', '\\'])),
+            };
+            let _ = write!(line, "{}{}={}", separator, escape_key(key), value);
+        }
+        let _ = write!(line, " {}", self.timestamp(precision));
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(measurement: &str) -> Point {
+        Point::new(
+            measurement.to_string(),
+            DateTime::parse_from_rfc3339("2024-01-01T12:00:00.123456789+01:00").unwrap(),
+        )
+    }
+
+    #[test]
+    fn escapes_measurement_tags_and_fields() {
+        let point = point("living room,east")
+            .add_tag("room name", "ground=floor,1")
+            .add_field("note", FieldValue::String("say \"hi\" \\o/".into()))
+            .add_field("field key", FieldValue::Float(1.5));
+        assert_eq!(
+            point.to_line(InfluxDbPrecision::S),
+            "living\\ room\\,east,room\\ name=ground\\=floor\\,1 \
+             note=\"say \\\"hi\\\" \\\\o/\",field\\ key=1.5 1704106800"
+        );
+    }
+
+    #[test]
+    fn sorts_tags_and_omits_empty_tag_values() {
+        let point = point("temperature")
+            .add_tag("station", "BS510")
+            .add_tag("room", "")
+            .add_tag("name", "Kitchen")
+            .add_field("value", FieldValue::Integer(-3));
+        assert_eq!(
+            point.to_line(InfluxDbPrecision::S),
+            "temperature,name=Kitchen,station=BS510 value=-3i 1704106800"
+        );
+    }
+
+    #[test]
+    fn renders_timestamp_in_precision() {
+        let point = point("temperature").add_field("value", FieldValue::Float(21.5));
+        assert!(point.to_line(InfluxDbPrecision::Ms).ends_with(" 1704106800123"));
+        assert!(point.to_line(InfluxDbPrecision::Us).ends_with(" 1704106800123456"));
+        assert!(point.to_line(InfluxDbPrecision::Ns).ends_with(" 1704106800123456789"));
+    }
+}
@@ -0,0 +1,341 @@
+//! HTTP client of the InfluxDB write endpoints.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::{InfluxDbApi, InfluxDbConfig, InfluxDbConsistency, InfluxDbPrecision};
+
+use super::InfluxDbError;
+
+enum InfluxDbAuth {
+    None,
+    Token(String),
+    Basic { username: String, password: String },
+}
+
+/// Sends line protocol to the v1 or v2 write endpoint.
+pub(super) struct InfluxDbWriter {
+    http: reqwest::Client,
+    write_url: String,
+    write_params: Vec<(&'static str, String)>,
+    auth: InfluxDbAuth,
+    max_retries: u32,
+    retry_delay: Duration,
+    /// Time for a write including its retries.
+    retry_budget: Duration,
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY_MS: u64 = 500;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Share of the sink timeout which is available for a write and its retries,
+/// the rest is left to the batching around it.
+const RETRY_BUDGET_PERCENT: u32 = 90;
+
+impl InfluxDbWriter {
+    /// Creates the writer, a write including its retries ends before the sink
+    /// `timeout` expires.
+    pub(super) fn new(config: &InfluxDbConfig, timeout: Duration) -> Result<Self, InfluxDbError> {
+        let auth = match (&config.username, &config.password, &config.token) {
+            (Some(username), Some(password), _) => InfluxDbAuth::Basic {
+                username: username.clone(),
+                password: password.clone(),
+            },
+            (_, _, Some(token)) => InfluxDbAuth::Token(token.clone()),
+            _ => InfluxDbAuth::None,
+        };
+        let http = build_http_client(config)?;
+        let url = config.url.trim_end_matches('/');
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            Ok(parsed) => {
+                return Err(InfluxDbError::InvalidUrl {
+                    url: config.url.clone(),
+                    message: format!("unsupported scheme `{}`", parsed.scheme()),
+                })
+            }
+            Err(error) => {
+                return Err(InfluxDbError::InvalidUrl {
+                    url: config.url.clone(),
+                    message: error.to_string(),
+                })
+            }
+        }
+        let (write_url, write_params) = match config.api {
+            InfluxDbApi::V1 => (
+                format!("{}/write", url),
+                [
+                    Some(("db", config.bucket.clone())),
+                    Some(("precision", v1_precision(config.precision).to_string())),
+                    config.retention_policy.clone().map(|rp| ("rp", rp)),
+                    config.consistency.map(|consistency| ("consistency", v1_consistency(consistency).to_string())),
+                ]
+                .into_iter()
+                .flatten()
+                .collect(),
+            ),
+            InfluxDbApi::V2 => (
+                format!("{}/api/v2/write", url),
+                vec![
+                    ("org", config.org.clone().unwrap_or_default()),
+                    ("bucket", config.bucket.clone()),
+                    ("precision", v2_precision(config.precision).to_string()),
+                ],
+            ),
+        };
+
+        Ok(InfluxDbWriter {
+            http,
+            write_url,
+            write_params,
+            auth,
+            max_retries: config.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            retry_delay: Duration::from_millis(config.retry_delay_ms.unwrap_or(DEFAULT_RETRY_DELAY_MS)),
+            retry_budget: timeout * RETRY_BUDGET_PERCENT / 100,
+        })
+    }
+
+    /// Sends line protocol to the write endpoint. Retryable errors are retried
+    /// with exponential backoff, honoring the `Retry-After` header, as long as
+    /// the retry budget allows. The last retry is cut short at the end of the
+    /// budget.
+    pub(super) async fn write(&self, lines: String) -> Result<(), InfluxDbError> {
+        let deadline = Instant::now() + self.retry_budget;
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let request_timeout = deadline.saturating_duration_since(Instant::now()).min(REQUEST_TIMEOUT);
+            match self.write_once(lines.clone(), request_timeout).await {
+                Err(error) if error.is_retryable() && attempt < self.max_retries => {
+                    let wait = match &error {
+                        InfluxDbError::Unavailable { retry_after: Some(retry_after), .. } => *retry_after,
+                        _ => delay,
+                    }
+                    .min(MAX_RETRY_DELAY);
+                    if Instant::now() + wait >= deadline {
+                        tracing::warn!("{}, no retry within the sink timeout", error);
+                        return Err(error);
+                    }
+                    attempt += 1;
+                    tracing::warn!("{}, retry {}/{} in {:?}", error, attempt, self.max_retries, wait);
+                    tokio::time::sleep(wait).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn write_once(&self, lines: String, timeout: Duration) -> Result<(), InfluxDbError> {
+        let request = self
+            .http
+            .post(&self.write_url)
+            .timeout(timeout)
+            .query(&self.write_params)
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(lines);
+        let request = match &self.auth {
+            InfluxDbAuth::None => request,
+            InfluxDbAuth::Token(token) => request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token)),
+            InfluxDbAuth::Basic { username, password } => request.basic_auth(username, Some(password)),
+        };
+        let response = request.send().await.map_err(|error| {
+            if error.is_connect() {
+                InfluxDbError::Connect(error)
+            } else {
+                InfluxDbError::Connection(error)
+            }
+        })?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(Duration::from_secs);
+            let message = response.text().await.unwrap_or_default();
+            Err(InfluxDbError::from_status(status.as_u16(), message, retry_after))
+        }
+    }
+}
+
+fn build_http_client(config: &InfluxDbConfig) -> Result<reqwest::Client, InfluxDbError> {
+    let mut builder = reqwest::Client::builder()
+        .danger_accept_invalid_certs(config.tls_skip_verify);
+    if let Some(path) = &config.ca_file {
+        let certificate_error = |message: String| InfluxDbError::Certificate { path: path.clone(), message };
+        let pem = std::fs::read(path).map_err(|error| certificate_error(error.to_string()))?;
+        let certificates =
+            reqwest::Certificate::from_pem_bundle(&pem).map_err(|error| certificate_error(error.to_string()))?;
+        if certificates.is_empty() {
+            return Err(certificate_error("no certificate found".into()));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder.build().map_err(InfluxDbError::Client)
+}
+
+fn v1_consistency(consistency: InfluxDbConsistency) -> &'static str {
+    match consistency {
+        InfluxDbConsistency::Any => "any",
+        InfluxDbConsistency::One => "one",
+        InfluxDbConsistency::Quorum => "quorum",
+        InfluxDbConsistency::All => "all",
+    }
+}
+
+fn v1_precision(precision: InfluxDbPrecision) -> &'static str {
+    match precision {
+        InfluxDbPrecision::Ns => "n",
+        InfluxDbPrecision::Us => "u",
+        InfluxDbPrecision::Ms => "ms",
+        InfluxDbPrecision::S => "s",
+    }
+}
+
+fn v2_precision(precision: InfluxDbPrecision) -> &'static str {
+    match precision {
+        InfluxDbPrecision::Ns => "ns",
+        InfluxDbPrecision::Us => "us",
+        InfluxDbPrecision::Ms => "ms",
+        InfluxDbPrecision::S => "s",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Accepts a single HTTP request, answers with `204 No Content` and
+    /// returns the request head and body.
+    async fn receive_request(listener: TcpListener) -> (String, String) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let request = read_request(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        request
+    }
+
+    /// Answers every request with `503 Service Unavailable` and counts them.
+    async fn refuse_requests(listener: TcpListener, requests: Arc<AtomicUsize>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_request(&mut stream).await;
+            requests.fetch_add(1, Ordering::SeqCst);
+            stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\nconnection: close\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> (String, String) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        let head_end = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed before the request head");
+            request.extend_from_slice(&buf[..n]);
+            if let Some(position) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position + 4;
+            }
+        };
+        let head = String::from_utf8(request[..head_end].to_vec()).unwrap();
+        let content_length: usize = head
+            .lines()
+            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse().unwrap()))
+            .unwrap_or(0);
+        while request.len() < head_end + content_length {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        let body = String::from_utf8(request[head_end..].to_vec()).unwrap();
+        (head, body)
+    }
+
+    #[tokio::test]
+    async fn writes_to_v2_api() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"http://{}/\"\napi = \"v2\"\norg = \"home\"\nbucket = \"arexx\"\ntoken = \"secret\"\nprecision = \"ms\"\n",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let writer = InfluxDbWriter::new(&config, Duration::from_secs(30)).unwrap();
+        let server = tokio::spawn(receive_request(listener));
+
+        let lines = "temperature,sensor_id=1111 value=21.5 1704110400000\n".to_string();
+        writer.write(lines.clone()).await.unwrap();
+        let (head, body) = server.await.unwrap();
+
+        let request_line = head.lines().next().unwrap();
+        assert_eq!(request_line, "POST /api/v2/write?org=home&bucket=arexx&precision=ms HTTP/1.1");
+        assert!(head.to_ascii_lowercase().contains("authorization: token secret\r\n"), "{}", head);
+        assert_eq!(body, lines);
+    }
+
+    #[tokio::test]
+    async fn writes_to_v1_api() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"http://{}\"\nbucket = \"arexx\"\nusername = \"arexx\"\npassword = \"hunter2\"\n\
+             retention-policy = \"weekly\"\nconsistency = \"quorum\"\nprecision = \"us\"\n",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let writer = InfluxDbWriter::new(&config, Duration::from_secs(30)).unwrap();
+        let server = tokio::spawn(receive_request(listener));
+
+        let lines = "temperature,sensor_id=1111 value=21.5 1704110400000000\n".to_string();
+        writer.write(lines.clone()).await.unwrap();
+        let (head, body) = server.await.unwrap();
+
+        let request_line = head.lines().next().unwrap();
+        assert_eq!(request_line, "POST /write?db=arexx&precision=u&rp=weekly&consistency=quorum HTTP/1.1");
+        let authorization = head
+            .lines()
+            .find_map(|line| line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("authorization")))
+            .map(|(_, value)| value.trim());
+        // base64 of "arexx:hunter2"
+        assert_eq!(authorization, Some("Basic YXJleHg6aHVudGVyMg=="), "{}", head);
+        assert_eq!(body, lines);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_within_the_sink_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"http://{}\"\nbucket = \"arexx\"\nmax-retries = 100\nretry-delay-ms = 100\n",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let timeout = Duration::from_secs(1);
+        let writer = InfluxDbWriter::new(&config, timeout).unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server = tokio::spawn(refuse_requests(listener, requests.clone()));
+
+        let started = Instant::now();
+        let result = writer.write("temperature value=21.5\n".to_string()).await;
+        assert!(matches!(result, Err(InfluxDbError::Unavailable { .. })), "{:?}", result);
+        assert!(started.elapsed() < timeout, "{:?}", started.elapsed());
+        // 100 ms, 200 ms and 400 ms backoff fit into the budget
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        server.abort();
+    }
+}
@@ -1,20 +1,107 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use anyhow::{Ok, Result, bail};
-use json::object;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use async_trait::async_trait;
+use rumqttc::{qos, AsyncClient, ClientError, EventLoop, MqttOptions, Publish, QoS, Transport};
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
 
 use crate::arexx::TemperatureReading;
-use crate::config::MqttConfig;
+use crate::config::{ConfigFile, MqttConfig, MqttConvention, MqttPayloadFormat, SensorConfig, SinkConfig, TimestampFormat};
+use crate::error::{ConfigError, SinkError};
+use crate::sink::slugify;
+use crate::template::{check_topic, Template};
 
-use crate::sink::Sink;
+use crate::sink::{Sink, SinkFactory, SinkHealth};
+
+use self::availability::{Availability, DEFAULT_STATUS, ONLINE};
+use self::connection::{Connection, ConnectionState};
+use self::discovery::{Announced, SensorTopics, DEFAULT_DISCOVERY_PREFIX};
+use self::homie::{Homie, HOMIE_STATUS};
+use self::payload::PayloadEncoder;
+
+mod availability;
+mod connection;
+mod discovery;
+mod homie;
+mod payload;
+
+#[derive(Debug, Error)]
+pub enum MqttError {
+    #[error("publish failed: {0}")]
+    Publish(#[from] ClientError),
+    #[error("can't read password file {path}: {source}")]
+    PasswordFile {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to load certificate {path}: {message}")]
+    Certificate { path: String, message: String },
+    #[error("{0}")]
+    InvalidQos(rumqttc::mqttbytes::Error),
+    #[error("not connected to MQTT broker {host}: {reason}")]
+    Disconnected { host: String, reason: String },
+    #[error("cannot encode payload: {0}")]
+    Encode(String),
+}
+
+impl MqttError {
+    /// A full request queue and a disconnected broker may recover, a closed
+    /// request queue (the event loop stopped), credentials and certificates
+    /// which cannot be loaded won't.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            MqttError::Publish(ClientError::TryRequest(_)) | MqttError::Disconnected { .. } => true,
+            MqttError::Publish(ClientError::Request(_)) => false,
+            MqttError::PasswordFile { .. }
+            | MqttError::Certificate { .. }
+            | MqttError::InvalidQos(_)
+            | MqttError::Encode(_) => false,
+        }
+    }
+}
+
+const DEFAULT_CLIENT_ID: &str = "arexx-mqtt";
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 5;
+const DEFAULT_QOS: u8 = 1;
+const AVAILABILITY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for the retained IDs of the announced sensors.
+const ANNOUNCED_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct MqttSink {
     host: String,
     client: AsyncClient,
     topic_base: String,
-    _eventloop: tokio::task::JoinHandle<()>,
+    topic: Option<Template>,
+    payload: PayloadEncoder,
+    qos: QoS,
+    retain: bool,
+    /// Sensors which override the `retain` setting.
+    sensor_retain: HashMap<u16, bool>,
+    homie: Option<Homie>,
+    availability: Arc<Availability>,
+    connection: Arc<Connection>,
+    /// Birth messages, published after every connect.
+    birth: Vec<(String, String)>,
+    /// Sensors announced to Home Assistant.
+    announced: Option<Arc<Announced>>,
+    /// Event loop of the client until the sink is started.
+    eventloop: Mutex<Option<EventLoop>>,
+    supervisor: Mutex<Option<JoinHandle<()>>>,
+    announce: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        for task in [&self.announce, &self.supervisor] {
+            if let Some(task) = task.lock().unwrap().take() {
+                task.abort();
+            }
+        }
+    }
 }
 
 impl Display for MqttSink {
@@ -23,52 +110,474 @@ impl Display for MqttSink {
     }
 }
 
+#[async_trait]
 impl Sink for MqttSink {
-    async fn publish(&self, reading: &TemperatureReading) -> Result<()> {
+    /// Connects to the broker and publishes the birth messages after every
+    /// connect.
+    async fn start(&self) -> Result<(), SinkError> {
+        let Some(eventloop) = self.eventloop.lock().unwrap().take() else {
+            return Ok(());
+        };
+        *self.supervisor.lock().unwrap() = Some(tokio::spawn(self.connection.clone().supervise(eventloop)));
+        *self.announce.lock().unwrap() = Some(tokio::spawn(announce(
+            self.client.clone(),
+            self.connection.clone(),
+            self.availability.clone(),
+            self.birth.clone(),
+            self.announced.clone(),
+            self.qos,
+        )));
+        Ok(())
+    }
+
+    async fn publish(&self, reading: &TemperatureReading) -> Result<(), SinkError> {
         tracing::trace!("publish MQTT {}", reading);
+        // don't queue readings in the client while the broker is unreachable
+        if let ConnectionState::Disconnected(reason) = self.connection.state() {
+            return Err(MqttError::Disconnected {
+                host: self.host.clone(),
+                reason,
+            }
+            .into());
+        }
+
+        let retain = self.sensor_retain.get(&reading.sensor).copied().unwrap_or(self.retain);
+        let (topic, value) = match &self.homie {
+            Some(homie) => match homie.property_topic(reading.sensor) {
+                Some(topic) => (topic.to_string(), reading.value.to_string().into_bytes()),
+                None => return Ok(()),
+            },
+            None => (self.format_topic(reading), self.payload.encode(reading)?),
+        };
+
+        if let Some(availability_topic) = self.availability.record(reading) {
+            self.client
+                .publish(availability_topic, self.qos, true, ONLINE)
+                .await
+                .map_err(MqttError::from)?;
+        }
+
+        self.client
+            .publish(topic, self.qos, retain, value)
+            .await
+            .map_err(MqttError::from)?;
+
+        Ok(())
+    }
 
-        let value = object! {
-            time: reading.timestamp.to_rfc3339(),
-            value: reading.value
+    async fn shutdown(&self) -> Result<(), SinkError> {
+        let Some(supervisor) = self.supervisor.lock().unwrap().take() else {
+            // never started
+            return Ok(());
+        };
+        if let Some(announce) = self.announce.lock().unwrap().take() {
+            announce.abort();
+        }
+        if self.connection.state() == ConnectionState::Connected {
+            self.availability.publish_offline(&self.client).await.map_err(MqttError::from)?;
         }
-        .dump();
-       
-        let res = self.client
-            .publish(self.format_topic(reading.sensor), QoS::AtLeastOnce, false, value)
-            .await;
+        self.connection.stop();
+        self.client.disconnect().await.map_err(MqttError::from)?;
+        // wait until the event loop sent the disconnect
+        let _ = supervisor.await;
+        Ok(())
+    }
 
-        match res {
-            std::result::Result::Ok(()) => Ok(()),
-            Err(_) => bail!("publish failed")
+    fn health(&self) -> SinkHealth {
+        match self.connection.state() {
+            ConnectionState::Connecting | ConnectionState::Connected => SinkHealth::Healthy,
+            ConnectionState::Disconnected(reason) => {
+                SinkHealth::Unhealthy(format!("not connected to {}: {}", self.host, reason))
+            }
         }
     }
 }
 
 impl MqttSink {
-    pub fn new(config: &MqttConfig) -> Result<Option<Self>> {
-        if config.enabled {
-            let mut mqtt_options = MqttOptions::new("arexx-mqtt", config.host.clone(), config.port);
-            mqtt_options.set_keep_alive(Duration::from_secs(5));
-
-            let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
-            let handle = tokio::spawn(async move {
-                while let std::result::Result::Ok(notification) = eventloop.poll().await {
-                    tracing::trace!("MQTT event = {:?}", notification);
+    /// Creates the sink for the sensors of the base station `station`. The
+    /// sink connects to the broker once it is started, see [`Sink::start`].
+    pub fn new(config: &MqttConfig, sensors: &[SensorConfig], station: &str) -> Result<Self, MqttError> {
+        let client_id = config.client_id.as_deref().unwrap_or(DEFAULT_CLIENT_ID);
+        let qos = qos(config.qos.unwrap_or(DEFAULT_QOS)).map_err(MqttError::InvalidQos)?;
+        // Homie properties are retained by default
+        let retain = config.retain.unwrap_or(config.convention == MqttConvention::Homie);
+        let sensor_retain: HashMap<u16, bool> = sensors
+            .iter()
+            .filter_map(|sensor| Some((sensor.id, sensor.mqtt_retain?)))
+            .collect();
+        let homie = match config.convention {
+            MqttConvention::Topics => None,
+            MqttConvention::Homie => Some(Homie::new(config, sensors, station, |sensor| {
+                sensor_retain.get(&sensor).copied().unwrap_or(retain)
+            })),
+        };
+        let (status_topic, status) = match &homie {
+            Some(homie) => (homie.state_topic(), HOMIE_STATUS),
+            None => (
+                config.status_topic.clone().unwrap_or_else(|| format!("{}/status", config.topic_base)),
+                DEFAULT_STATUS,
+            ),
+        };
+        let availability = Arc::new(Availability::new(
+            status_topic,
+            status,
+            qos,
+            config.sensor_timeout_secs.map(Duration::from_secs),
+            &config.topic_base,
+            sensors,
+        ));
+        let mut mqtt_options = MqttOptions::new(client_id, config.host.clone(), config.port);
+        mqtt_options.set_keep_alive(Duration::from_secs(config.keep_alive_secs.unwrap_or(DEFAULT_KEEP_ALIVE_SECS)));
+        mqtt_options.set_clean_session(config.clean_session.unwrap_or(true));
+        mqtt_options.set_last_will(availability.last_will());
+        if let Some(username) = &config.username {
+            mqtt_options.set_credentials(username, read_password(config)?.unwrap_or_default());
+        }
+        if config.tls {
+            mqtt_options.set_transport(tls_transport(config)?);
+        }
+
+        let (client, eventloop) = AsyncClient::new(mqtt_options, 10);
+        let connection = Connection::new(format!("{}:{}", config.host, config.port));
+        let payload = PayloadEncoder::new(config);
+        let announced = match &homie {
+            None if config.homeassistant_discovery => {
+                let prefix = config.discovery_prefix.as_deref().unwrap_or(DEFAULT_DISCOVERY_PREFIX);
+                Some(Arc::new(Announced::new(prefix, station, sensors)))
+            }
+            _ => None,
+        };
+        let birth = match (&homie, &announced) {
+            (Some(homie), _) => homie.attributes().to_vec(),
+            (None, Some(announced)) => {
+                let value_template = payload.value_template().unwrap_or_default();
+                let mut birth = discovery_messages(config, sensors, station, &availability, value_template);
+                birth.push((announced.topic(), announced.payload()));
+                birth
+            }
+            (None, None) => Vec::new(),
+        };
+
+        Ok(MqttSink {
+            client,
+            topic_base: config.topic_base.to_string(),
+            topic: config.topic.clone(),
+            payload,
+            qos,
+            retain,
+            sensor_retain,
+            homie,
+            host: config.host.to_string(),
+            availability,
+            connection,
+            birth,
+            announced,
+            eventloop: Mutex::new(Some(eventloop)),
+            supervisor: Mutex::new(None),
+            announce: Mutex::new(None),
+        })
+    }
+
+    fn format_topic(&self, reading: &TemperatureReading) -> String {
+        match &self.topic {
+            Some(template) => template.render(reading, slugify),
+            None => format!("{}/{}", self.topic_base, slugify(&reading.name)),
+        }
+    }
+}
+
+/// Unit and Home Assistant device class of the sensor kinds.
+fn kind_defaults(kind: &str) -> (Option<&'static str>, Option<&'static str>) {
+    match kind {
+        "temperature" => (Some("°C"), Some("temperature")),
+        "humidity" => (Some("%"), Some("humidity")),
+        _ => (None, None),
+    }
+}
+
+/// Home Assistant discovery messages of the sensors.
+fn discovery_messages(
+    config: &MqttConfig,
+    sensors: &[SensorConfig],
+    station: &str,
+    availability: &Availability,
+    value_template: Option<String>,
+) -> Vec<(String, String)> {
+    let prefix = config.discovery_prefix.as_deref().unwrap_or(DEFAULT_DISCOVERY_PREFIX);
+    sensors
+        .iter()
+        .map(|sensor| {
+            let state = match &config.topic {
+                Some(template) => template.render_sensor(sensor, station, slugify),
+                None => format!("{}/{}", config.topic_base, slugify(&sensor.name)),
+            };
+            let mut availability_topics = vec![availability.status_topic().to_string()];
+            if availability.has_sensor_timeout() {
+                availability_topics.push(availability::sensor_topic(&config.topic_base, sensor));
+            }
+            let topics = SensorTopics {
+                state,
+                availability: availability_topics,
+                value_template: value_template.clone(),
+            };
+            discovery::sensor_config(prefix, station, sensor, topics)
+        })
+        .collect()
+}
+
+async fn publish_birth(client: &AsyncClient, birth: &[(String, String)], qos: QoS) -> Result<(), ClientError> {
+    for (topic, payload) in birth {
+        tracing::debug!("MQTT birth message {}", topic);
+        client.publish(topic, qos, true, payload.as_str()).await?;
+    }
+    Ok(())
+}
+
+/// Clears the discovery config messages of the sensors which were announced
+/// by the previous run, but are no longer configured.
+async fn clear_removed_sensors(
+    client: &AsyncClient,
+    incoming: &mut broadcast::Receiver<Publish>,
+    announced: &Announced,
+    qos: QoS,
+) -> Result<(), ClientError> {
+    let topic = announced.topic();
+    client.subscribe(&topic, qos).await?;
+    // the broker sends nothing if no message is retained
+    let previous = tokio::time::timeout(ANNOUNCED_TIMEOUT, async {
+        loop {
+            match incoming.recv().await {
+                Ok(publish) if publish.topic == topic => break Some(publish.payload),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break None,
+            }
+        }
+    });
+    let previous = previous.await.ok().flatten();
+    client.unsubscribe(&topic).await?;
+    for config_topic in previous.map(|payload| announced.removed_config_topics(&payload)).unwrap_or_default() {
+        tracing::info!("MQTT clearing discovery config {}", config_topic);
+        client.publish(config_topic, qos, true, Vec::new()).await?;
+    }
+    Ok(())
+}
+
+/// Publishes the retained birth messages, e.g. the discovery messages, and
+/// the availability after every connect, and `offline` for the sensors which
+/// timed out. The discovery configs of removed sensors are cleared after the
+/// first connect.
+async fn announce(
+    client: AsyncClient,
+    connection: Arc<Connection>,
+    availability: Arc<Availability>,
+    birth: Vec<(String, String)>,
+    mut announced: Option<Arc<Announced>>,
+    qos: QoS,
+) {
+    let mut incoming = connection.incoming();
+    let mut check = tokio::time::interval(AVAILABILITY_CHECK_INTERVAL);
+    loop {
+        let result = tokio::select! {
+            _ = connection.connected() => {
+                let cleared = match announced.take() {
+                    Some(announced) => clear_removed_sensors(&client, &mut incoming, &announced, qos).await,
+                    None => Ok(()),
+                };
+                match cleared {
+                    Ok(()) => match publish_birth(&client, &birth, qos).await {
+                        Ok(()) => availability.publish_online(&client).await,
+                        error => error,
+                    },
+                    error => error,
                 }
-            });
+            },
+            _ = check.tick(), if availability.has_sensor_timeout() => availability.publish_expired(&client).await,
+        };
+        if let Err(error) = result {
+            tracing::debug!("MQTT announcements stopped: {}", error);
+            break;
+        }
+    }
+}
+
+/// The configured password or the first line of the password file.
+fn read_password(config: &MqttConfig) -> Result<Option<String>, MqttError> {
+    match (&config.password, &config.password_file) {
+        (Some(password), _) => Ok(Some(password.clone())),
+        (None, Some(path)) => {
+            let content = std::fs::read_to_string(path)
+                .map_err(|source| MqttError::PasswordFile { path: path.clone(), source })?;
+            Ok(Some(content.lines().next().unwrap_or_default().to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, MqttError> {
+    let certificate_error = |message: String| MqttError::Certificate { path: path.to_string(), message };
+    let pem = std::fs::read(path).map_err(|error| certificate_error(error.to_string()))?;
+    if !String::from_utf8_lossy(&pem).contains("-----BEGIN ") {
+        return Err(certificate_error("no PEM data found".into()));
+    }
+    Ok(pem)
+}
 
-            Ok(Some(MqttSink {
-                client,
-                topic_base: config.topic_base.to_string(),
-                host: config.host.to_string(),
-                _eventloop: handle,
-            }))
-        } else {
-            Ok(None)
+/// TLS with the configured CA and optional client certificate, or with the
+/// platform certificates.
+fn tls_transport(config: &MqttConfig) -> Result<Transport, MqttError> {
+    match &config.ca_file {
+        Some(ca_file) => {
+            let client_auth = match (&config.client_cert, &config.client_key) {
+                (Some(cert), Some(key)) => Some((read_pem(cert)?, read_pem(key)?)),
+                _ => None,
+            };
+            Ok(Transport::tls(read_pem(ca_file)?, client_auth, None))
+        }
+        None => Ok(Transport::tls_with_default_config()),
+    }
+}
+
+/// Creates MQTT sinks, `type = "MQTT"`.
+pub struct MqttSinkFactory;
+
+impl SinkFactory for MqttSinkFactory {
+    fn type_name(&self) -> &str {
+        "MQTT"
+    }
+
+    fn validate(&self, config: &SinkConfig, config_file: &ConfigFile) -> Result<(), ConfigError> {
+        let config: MqttConfig = config.parse()?;
+        if let Err(reason) = check_topic(&config.topic_base) {
+            return Err(ConfigError::Invalid(format!("MQTT: `topic-base` `{}` {}", config.topic_base, reason)));
+        }
+        if let Some(topic) = &config.topic {
+            topic.validate_topic(&config_file.sensors, &config_file.station()?)?;
+        }
+        if config.password.is_some() && config.password_file.is_some() {
+            return Err(ConfigError::Invalid("MQTT: `password` and `password-file` are exclusive".into()));
+        }
+        if config.username.is_none() && (config.password.is_some() || config.password_file.is_some()) {
+            return Err(ConfigError::Invalid("MQTT: a password requires `username`".into()));
+        }
+        if config.client_cert.is_some() != config.client_key.is_some() {
+            return Err(ConfigError::Invalid("MQTT: `client-cert` and `client-key` must be set together".into()));
+        }
+        if config.client_cert.is_some() && config.ca_file.is_none() {
+            return Err(ConfigError::Invalid("MQTT: `client-cert` requires `ca-file`".into()));
+        }
+        if !config.tls && (config.ca_file.is_some() || config.client_cert.is_some()) {
+            return Err(ConfigError::Invalid("MQTT: `ca-file` and `client-cert` require `tls = true`".into()));
         }
+        if config.qos.is_some_and(|qos| qos > 2) {
+            return Err(ConfigError::Invalid("MQTT: `qos` must be 0, 1 or 2".into()));
+        }
+        if config.keep_alive_secs.is_some_and(|secs| secs > u16::MAX.into()) {
+            // the keep-alive of the CONNECT packet is a 16 bit value
+            return Err(ConfigError::Invalid(format!("MQTT: `keep-alive-secs` must not exceed {}", u16::MAX)));
+        }
+        if config.sensor_timeout_secs == Some(0) {
+            return Err(ConfigError::Invalid("MQTT: `sensor-timeout-secs` must be greater than 0".into()));
+        }
+        if config.convention == MqttConvention::Homie {
+            if config.topic.is_some()
+                || config.status_topic.is_some()
+                || config.homeassistant_discovery
+                || config.sensor_timeout_secs.is_some()
+            {
+                return Err(ConfigError::Invalid(
+                    "MQTT: `topic`, `status-topic`, `homeassistant-discovery` and `sensor-timeout-secs` are not supported with the Homie convention".into(),
+                ));
+            }
+            if config.format != MqttPayloadFormat::Json
+                || config.fields.is_some()
+                || config.timestamp_format != TimestampFormat::Rfc3339
+            {
+                return Err(ConfigError::Invalid(
+                    "MQTT: the Homie convention publishes plain values, `format`, `fields` and `timestamp-format` are not supported".into(),
+                ));
+            }
+            if let Some(device_id) = &config.homie_device_id {
+                if slugify(device_id) != *device_id {
+                    return Err(ConfigError::Invalid(format!(
+                        "MQTT: `homie-device-id` `{}` may only contain lowercase letters, digits and hyphens",
+                        device_id
+                    )));
+                }
+            }
+            let invalid_kind = |sensor: &&SensorConfig| sensor.kind.as_deref().is_some_and(|kind| slugify(kind).is_empty());
+            if let Some(sensor) = config_file.sensors.iter().find(invalid_kind) {
+                return Err(ConfigError::Invalid(format!(
+                    "MQTT: sensor {} ({}): `kind` is no valid Homie property ID",
+                    sensor.id, sensor.name
+                )));
+            }
+        }
+        PayloadEncoder::validate(&config, &config_file.sensors)?;
+        if config.homeassistant_discovery {
+            PayloadEncoder::new(&config).value_template()?;
+        }
+        if config.clean_session == Some(false) && config.client_id.is_none() {
+            return Err(ConfigError::Invalid("MQTT: a persistent session (`clean-session = false`) requires `client-id`".into()));
+        }
+        Ok(())
+    }
+
+    fn create(&self, config: &SinkConfig, config_file: &ConfigFile) -> Result<Box<dyn Sink>, SinkError> {
+        Ok(Box::new(MqttSink::new(&config.parse()?, &config_file.sensors, &config_file.station()?)?))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
 
-    fn format_topic(&self, sensor: u16) -> String {
-        format!("{}/{}", self.topic_base, sensor)
+    fn validate(settings: &str) -> Result<(), ConfigError> {
+        let config: ConfigFile = toml::from_str(&format!(
+            "sensors = []\n[[sink]]\ntype = \"MQTT\"\nenabled = true\nhost = \"localhost\"\nport = 1883\ntopic-base = \"arexx\"\n{}",
+            settings
+        ))
+        .unwrap();
+        MqttSinkFactory.validate(&config.sink[0], &config)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_sensor_timeout_with_homie_convention() {
+        assert!(validate("convention = \"homie\"").is_ok());
+        assert!(validate("sensor-timeout-secs = 900").is_ok());
+        assert!(validate("convention = \"homie\"\nsensor-timeout-secs = 900").is_err());
+    }
+
+    #[test]
+    fn rejects_keep_alive_beyond_16_bits() {
+        assert!(validate("keep-alive-secs = 0").is_ok());
+        assert!(validate("keep-alive-secs = 65535").is_ok());
+        assert!(validate("keep-alive-secs = 65536").is_err());
+    }
+
+    #[tokio::test]
+    async fn connects_when_started() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: MqttConfig = toml::from_str(&format!(
+            "host = \"127.0.0.1\"\nport = {}\ntopic-base = \"arexx\"\n",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let sink = MqttSink::new(&config, &[], "BS510").unwrap();
+
+        let accept = tokio::time::timeout(Duration::from_millis(300), listener.accept());
+        assert!(accept.await.is_err(), "connected before start");
+
+        sink.start().await.unwrap();
+        let accept = tokio::time::timeout(Duration::from_secs(5), listener.accept());
+        assert!(accept.await.is_ok(), "not connected after start");
+    }
+
+    #[tokio::test]
+    async fn shuts_down_without_start() {
+        let config: MqttConfig = toml::from_str("host = \"127.0.0.1\"\nport = 1\ntopic-base = \"arexx\"\n").unwrap();
+        let sink = MqttSink::new(&config, &[], "BS510").unwrap();
+        assert!(sink.shutdown().await.is_ok());
+    }
+}
@@ -0,0 +1,183 @@
+//! Availability of arexx-tap and of the sensors.
+//!
+//! The status topic is `online` while arexx-tap is connected and `offline`
+//! after it stopped, the broker publishes the last will if the connection
+//! is lost. The Homie convention uses other payloads, see
+//! [`StatusPayloads`]. With a sensor timeout, the availability topic of
+//! each sensor is `offline` if the sensor sent no reading within the
+//! timeout.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use rumqttc::{AsyncClient, ClientError, LastWill, QoS};
+
+use crate::arexx::TemperatureReading;
+use crate::config::SensorConfig;
+use crate::sink::slugify;
+
+pub(super) const ONLINE: &str = "online";
+pub(super) const OFFLINE: &str = "offline";
+
+/// Payloads of the status topic.
+pub(super) struct StatusPayloads {
+    /// Published after every connect.
+    pub(super) online: &'static str,
+    /// Last will, published by the broker when the connection is lost.
+    pub(super) lost: &'static str,
+    /// Published on shutdown.
+    pub(super) offline: &'static str,
+}
+
+pub(super) const DEFAULT_STATUS: StatusPayloads = StatusPayloads {
+    online: ONLINE,
+    lost: OFFLINE,
+    offline: OFFLINE,
+};
+
+struct SensorAvailability {
+    topic: String,
+    last_reading: Option<DateTime<FixedOffset>>,
+    online: bool,
+}
+
+pub(super) struct Availability {
+    status_topic: String,
+    status: StatusPayloads,
+    qos: QoS,
+    sensor_timeout: Option<Duration>,
+    sensors: Mutex<BTreeMap<u16, SensorAvailability>>,
+}
+
+/// Availability topic of a sensor.
+pub(super) fn sensor_topic(topic_base: &str, sensor: &SensorConfig) -> String {
+    format!("{}/{}/availability", topic_base, slugify(&sensor.name))
+}
+
+impl Availability {
+    pub(super) fn new(
+        status_topic: String,
+        status: StatusPayloads,
+        qos: QoS,
+        sensor_timeout: Option<Duration>,
+        topic_base: &str,
+        sensors: &[SensorConfig],
+    ) -> Availability {
+        let sensors = match sensor_timeout {
+            Some(_) => sensors
+                .iter()
+                .map(|sensor| {
+                    let availability = SensorAvailability {
+                        topic: sensor_topic(topic_base, sensor),
+                        last_reading: None,
+                        online: false,
+                    };
+                    (sensor.id, availability)
+                })
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        Availability {
+            status_topic,
+            status,
+            qos,
+            sensor_timeout,
+            sensors: Mutex::new(sensors),
+        }
+    }
+
+    pub(super) fn status_topic(&self) -> &str {
+        &self.status_topic
+    }
+
+    /// Whether the availability of every sensor is published.
+    pub(super) fn has_sensor_timeout(&self) -> bool {
+        self.sensor_timeout.is_some()
+    }
+
+    pub(super) fn last_will(&self) -> LastWill {
+        LastWill::new(&self.status_topic, self.status.lost, self.qos, true)
+    }
+
+    /// Records the reading of a sensor. Returns the availability topic of
+    /// the sensor if it became available.
+    pub(super) fn record(&self, reading: &TemperatureReading) -> Option<String> {
+        let timeout = self.sensor_timeout?;
+        let mut sensors = self.sensors.lock().unwrap();
+        let sensor = sensors.get_mut(&reading.sensor)?;
+        if sensor.last_reading.is_none_or(|last_reading| reading.timestamp > last_reading) {
+            sensor.last_reading = Some(reading.timestamp);
+        }
+        if sensor.online || is_expired(sensor.last_reading, timeout) {
+            return None;
+        }
+        sensor.online = true;
+        Some(sensor.topic.clone())
+    }
+
+    /// Marks the sensors without a reading within the timeout unavailable
+    /// and returns their availability topics.
+    fn expire(&self) -> Vec<String> {
+        let Some(timeout) = self.sensor_timeout else {
+            return Vec::new();
+        };
+        let mut sensors = self.sensors.lock().unwrap();
+        sensors
+            .values_mut()
+            .filter(|sensor| sensor.online && is_expired(sensor.last_reading, timeout))
+            .map(|sensor| {
+                sensor.online = false;
+                sensor.topic.clone()
+            })
+            .collect()
+    }
+
+    /// Availability topics and states of all sensors.
+    fn sensor_states(&self) -> Vec<(String, &'static str)> {
+        let sensors = self.sensors.lock().unwrap();
+        sensors
+            .values()
+            .map(|sensor| (sensor.topic.clone(), if sensor.online { ONLINE } else { OFFLINE }))
+            .collect()
+    }
+
+    async fn publish(&self, client: &AsyncClient, topic: String, state: &str) -> Result<(), ClientError> {
+        tracing::debug!("MQTT availability {} = {}", topic, state);
+        client.publish(topic, self.qos, true, state).await
+    }
+
+    /// Publishes `online` and the availability of the sensors.
+    pub(super) async fn publish_online(&self, client: &AsyncClient) -> Result<(), ClientError> {
+        self.publish(client, self.status_topic.clone(), self.status.online).await?;
+        for (topic, state) in self.sensor_states() {
+            self.publish(client, topic, state).await?;
+        }
+        Ok(())
+    }
+
+    /// Publishes `offline` on shutdown, the broker doesn't publish the last
+    /// will after a disconnect.
+    pub(super) async fn publish_offline(&self, client: &AsyncClient) -> Result<(), ClientError> {
+        self.publish(client, self.status_topic.clone(), self.status.offline).await
+    }
+
+    /// Publishes `offline` for the sensors which timed out.
+    pub(super) async fn publish_expired(&self, client: &AsyncClient) -> Result<(), ClientError> {
+        for topic in self.expire() {
+            self.publish(client, topic, OFFLINE).await?;
+        }
+        Ok(())
+    }
+}
+
+fn is_expired(last_reading: Option<DateTime<FixedOffset>>, timeout: Duration) -> bool {
+    match last_reading {
+        Some(last_reading) => Utc::now()
+            .signed_duration_since(last_reading)
+            .to_std()
+            .is_ok_and(|age| age > timeout),
+        None => true,
+    }
+}
@@ -0,0 +1,108 @@
+//! Supervision of the MQTT event loop.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rumqttc::{ConnectionError, Event, EventLoop, Outgoing, Packet, Publish};
+use tokio::sync::{broadcast, Notify};
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const INCOMING_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected(String),
+}
+
+/// Connection state of the event loop, shared with the sink.
+pub(super) struct Connection {
+    host: String,
+    state: Mutex<ConnectionState>,
+    stopping: AtomicBool,
+    connects: Notify,
+    /// Messages of the subscribed topics.
+    incoming: broadcast::Sender<Publish>,
+}
+
+impl Connection {
+    pub(super) fn new(host: String) -> Arc<Connection> {
+        Arc::new(Connection {
+            host,
+            state: Mutex::new(ConnectionState::Connecting),
+            stopping: AtomicBool::new(false),
+            connects: Notify::new(),
+            incoming: broadcast::channel(INCOMING_CAPACITY).0,
+        })
+    }
+
+    pub(super) fn state(&self) -> ConnectionState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Completes after the next connect to the broker.
+    pub(super) async fn connected(&self) {
+        self.connects.notified().await
+    }
+
+    /// Receives the messages of the subscribed topics which arrive from now
+    /// on.
+    pub(super) fn incoming(&self) -> broadcast::Receiver<Publish> {
+        self.incoming.subscribe()
+    }
+
+    /// Ends the supervision once the client disconnected, instead of
+    /// reconnecting.
+    pub(super) fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        let mut current = self.state.lock().unwrap();
+        match (&*current, &state) {
+            (ConnectionState::Connected, ConnectionState::Connected) => {}
+            (_, ConnectionState::Connected) => tracing::info!("MQTT connected to {}", self.host),
+            (ConnectionState::Connected, ConnectionState::Disconnected(error)) => {
+                tracing::warn!("MQTT connection to {} lost: {}", self.host, error)
+            }
+            (ConnectionState::Connecting, ConnectionState::Disconnected(error)) => {
+                tracing::warn!("MQTT cannot connect to {}: {}", self.host, error)
+            }
+            (_, _) => {}
+        }
+        *current = state;
+    }
+
+    /// Polls the event loop, which reconnects after a connection error. The
+    /// reconnects are delayed with exponential backoff.
+    pub(super) async fn supervise(self: Arc<Self>, mut eventloop: EventLoop) {
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    self.set_state(ConnectionState::Connected);
+                    self.connects.notify_one();
+                    delay = MIN_RECONNECT_DELAY;
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    // nobody may be waiting for it
+                    let _ = self.incoming.send(publish);
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                Ok(notification) => tracing::trace!("MQTT event = {:?}", notification),
+                Err(ConnectionError::RequestsDone) => break,
+                Err(_) if self.stopping.load(Ordering::Relaxed) => break,
+                Err(error) => {
+                    self.set_state(ConnectionState::Disconnected(error.to_string()));
+                    tracing::debug!("MQTT reconnecting to {} in {:?}", self.host, delay);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+        tracing::debug!("MQTT event loop of {} stopped", self.host);
+    }
+}
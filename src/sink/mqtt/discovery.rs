@@ -0,0 +1,167 @@
+//! Home Assistant MQTT discovery.
+//!
+//! Every configured sensor is announced with a retained config message at
+//! `<prefix>/sensor/arexx-<station>/<sensor id>/config`, so that Home
+//! Assistant creates a sensor entity of the base station device. The IDs of
+//! the announced sensors are retained at
+//! `<prefix>/sensor/arexx-<station>/announced`, the config messages of
+//! sensors which are no longer configured are cleared after a restart.
+
+use json::{array, object, JsonValue};
+
+use crate::config::{SensorConfig, DEFAULT_SENSOR_KIND};
+use crate::sink::slugify;
+
+use super::kind_defaults;
+
+pub(super) const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Topics of a sensor referenced by its discovery message.
+pub(super) struct SensorTopics {
+    pub(super) state: String,
+    pub(super) availability: Vec<String>,
+    /// Template which extracts the value from the payload.
+    pub(super) value_template: Option<String>,
+}
+
+/// The sensors announced by the previous and the current run.
+pub(super) struct Announced {
+    prefix: String,
+    node_id: String,
+    sensors: Vec<u16>,
+}
+
+impl Announced {
+    pub(super) fn new(prefix: &str, station: &str, sensors: &[SensorConfig]) -> Self {
+        Announced {
+            prefix: prefix.to_string(),
+            node_id: node_id(station),
+            sensors: sensors.iter().map(|sensor| sensor.id).collect(),
+        }
+    }
+
+    /// Retained topic with the IDs of the announced sensors.
+    pub(super) fn topic(&self) -> String {
+        format!("{}/sensor/{}/announced", self.prefix, self.node_id)
+    }
+
+    /// The IDs of the configured sensors as JSON array.
+    pub(super) fn payload(&self) -> String {
+        JsonValue::from(self.sensors.clone()).dump()
+    }
+
+    /// Config topics of the sensors in the `previous` announced payload
+    /// which are no longer configured. Invalid payloads are ignored.
+    pub(super) fn removed_config_topics(&self, previous: &[u8]) -> Vec<String> {
+        let previous = std::str::from_utf8(previous).ok().and_then(|payload| json::parse(payload).ok());
+        let Some(previous) = previous else {
+            return Vec::new();
+        };
+        previous
+            .members()
+            .filter_map(JsonValue::as_u16)
+            .filter(|sensor| !self.sensors.contains(sensor))
+            .map(|sensor| config_topic(&self.prefix, &self.node_id, sensor))
+            .collect()
+    }
+}
+
+fn node_id(station: &str) -> String {
+    format!("arexx-{}", slugify(station))
+}
+
+fn config_topic(prefix: &str, node_id: &str, sensor: u16) -> String {
+    format!("{}/sensor/{}/{}/config", prefix, node_id, sensor)
+}
+
+/// Discovery config message of a sensor, returns the topic and payload.
+pub(super) fn sensor_config(prefix: &str, station: &str, sensor: &SensorConfig, topics: SensorTopics) -> (String, String) {
+    let node_id = node_id(station);
+    let kind = sensor.kind.as_deref().unwrap_or(DEFAULT_SENSOR_KIND);
+    let (default_unit, default_device_class) = kind_defaults(kind);
+
+    let mut availability = array![];
+    for topic in topics.availability {
+        let _ = availability.push(object! { topic: topic });
+    }
+    let mut payload = object! {
+        name: sensor.name.clone(),
+        unique_id: format!("{}-{}", node_id, sensor.id),
+        state_topic: topics.state,
+        state_class: "measurement",
+        availability: availability,
+        availability_mode: "all",
+        device: object! {
+            identifiers: array![node_id.clone()],
+            name: format!("Arexx {}", station),
+            manufacturer: "Arexx",
+        },
+    };
+    if let Some(value_template) = topics.value_template {
+        payload["value_template"] = JsonValue::from(value_template);
+    }
+    if let Some(unit) = sensor.unit.as_deref().or(default_unit) {
+        payload["unit_of_measurement"] = JsonValue::from(unit);
+    }
+    if let Some(device_class) = sensor.device_class.as_deref().or(default_device_class) {
+        payload["device_class"] = JsonValue::from(device_class);
+    }
+
+    (config_topic(prefix, &node_id, sensor.id), payload.dump())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(toml: &str) -> SensorConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn topics(value_template: Option<&str>) -> SensorTopics {
+        SensorTopics {
+            state: "arexx/cellar".into(),
+            availability: vec!["arexx/status".into(), "arexx/cellar/availability".into()],
+            value_template: value_template.map(String::from),
+        }
+    }
+
+    #[test]
+    fn announces_sensor_with_kind_defaults() {
+        let sensor = sensor("id = 1111\nname = \"Cellar\"");
+        let (topic, payload) = sensor_config("homeassistant", "Home Cellar", &sensor, topics(Some("{{ value_json.value }}")));
+        assert_eq!(topic, "homeassistant/sensor/arexx-home-cellar/1111/config");
+        let payload = json::parse(&payload).unwrap();
+        assert_eq!(payload["unique_id"], "arexx-home-cellar-1111");
+        assert_eq!(payload["state_topic"], "arexx/cellar");
+        assert_eq!(payload["value_template"], "{{ value_json.value }}");
+        assert_eq!(payload["availability"][1]["topic"], "arexx/cellar/availability");
+        assert_eq!(payload["unit_of_measurement"], "°C");
+        assert_eq!(payload["device_class"], "temperature");
+        assert_eq!(payload["device"]["identifiers"][0], "arexx-home-cellar");
+        assert_eq!(payload["device"]["name"], "Arexx Home Cellar");
+    }
+
+    #[test]
+    fn announces_sensor_with_configured_unit() {
+        let sensor = sensor("id = 2222\nname = \"Level\"\nkind = \"level\"\nunit = \"cm\"");
+        let (_, payload) = sensor_config("ha", "BS510", &sensor, topics(None));
+        let payload = json::parse(&payload).unwrap();
+        assert_eq!(payload["unit_of_measurement"], "cm");
+        assert!(payload["device_class"].is_null());
+        assert!(payload["value_template"].is_null());
+    }
+
+    #[test]
+    fn clears_sensors_which_are_no_longer_configured() {
+        let sensors = [sensor("id = 1111\nname = \"Cellar\""), sensor("id = 2222\nname = \"Garden\"")];
+        let announced = Announced::new("homeassistant", "BS510", &sensors);
+        assert_eq!(announced.topic(), "homeassistant/sensor/arexx-bs510/announced");
+        assert_eq!(announced.payload(), "[1111,2222]");
+        assert_eq!(
+            announced.removed_config_topics(b"[1111, 3333, 2222]"),
+            vec!["homeassistant/sensor/arexx-bs510/3333/config"]
+        );
+        assert!(announced.removed_config_topics(b"not json").is_empty());
+    }
+}
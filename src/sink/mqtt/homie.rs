@@ -0,0 +1,152 @@
+//! Homie convention 4.0.
+//!
+//! The base station is published as device `<prefix>/<device id>` and every
+//! sensor as node `sensor-<id>` with one property named after the sensor
+//! kind, e.g. `homie/arexx-bs510/sensor-1111/temperature`. The device state
+//! is `init` while the attributes are published after a connect, then
+//! `ready`, `disconnected` on shutdown and `lost` (last will) when the
+//! connection is lost.
+
+use std::collections::HashMap;
+
+use crate::config::{MqttConfig, SensorConfig, DEFAULT_SENSOR_KIND};
+use crate::sink::slugify;
+
+use super::availability::StatusPayloads;
+use super::kind_defaults;
+
+pub(super) const DEFAULT_HOMIE_PREFIX: &str = "homie";
+const HOMIE_VERSION: &str = "4.0.0";
+
+pub(super) const HOMIE_STATUS: StatusPayloads = StatusPayloads {
+    online: "ready",
+    lost: "lost",
+    offline: "disconnected",
+};
+
+/// Default device ID of a base station, `arexx-<station>`.
+fn default_device_id(station: &str) -> String {
+    slugify(&format!("arexx {}", station))
+}
+
+/// Homie ID of the node of a sensor.
+fn node_id(sensor: &SensorConfig) -> String {
+    format!("sensor-{}", sensor.id)
+}
+
+/// Homie ID of the property of a sensor, the sensor kind.
+fn property_id(sensor: &SensorConfig) -> String {
+    slugify(sensor.kind.as_deref().unwrap_or(DEFAULT_SENSOR_KIND))
+}
+
+pub(super) struct Homie {
+    device_topic: String,
+    /// Property topic of every sensor.
+    properties: HashMap<u16, String>,
+    /// Device, node and property attributes, published after every connect.
+    attributes: Vec<(String, String)>,
+}
+
+impl Homie {
+    pub(super) fn new(config: &MqttConfig, sensors: &[SensorConfig], station: &str, retain: impl Fn(u16) -> bool) -> Homie {
+        let prefix = config.homie_prefix.as_deref().unwrap_or(DEFAULT_HOMIE_PREFIX);
+        let device_id = config.homie_device_id.clone().unwrap_or_else(|| default_device_id(station));
+        let device_topic = format!("{}/{}", prefix, device_id);
+
+        let nodes: Vec<String> = sensors.iter().map(node_id).collect();
+        let mut attributes = vec![
+            (format!("{}/$state", device_topic), "init".to_string()),
+            (format!("{}/$homie", device_topic), HOMIE_VERSION.to_string()),
+            (format!("{}/$name", device_topic), format!("Arexx {}", station)),
+            (format!("{}/$nodes", device_topic), nodes.join(",")),
+            (format!("{}/$extensions", device_topic), String::new()),
+        ];
+        let mut properties = HashMap::new();
+        for sensor in sensors {
+            let node_topic = format!("{}/{}", device_topic, node_id(sensor));
+            let property = property_id(sensor);
+            let property_topic = format!("{}/{}", node_topic, property);
+            let kind = sensor.kind.as_deref().unwrap_or(DEFAULT_SENSOR_KIND);
+
+            attributes.push((format!("{}/$name", node_topic), sensor.name.clone()));
+            attributes.push((format!("{}/$type", node_topic), format!("Arexx {} sensor", kind)));
+            attributes.push((format!("{}/$properties", node_topic), property.clone()));
+            attributes.push((format!("{}/$name", property_topic), kind.to_string()));
+            attributes.push((format!("{}/$datatype", property_topic), "float".to_string()));
+            attributes.push((format!("{}/$retained", property_topic), retain(sensor.id).to_string()));
+            if let Some(unit) = sensor.unit.as_deref().or(kind_defaults(kind).0) {
+                attributes.push((format!("{}/$unit", property_topic), unit.to_string()));
+            }
+            properties.insert(sensor.id, property_topic);
+        }
+
+        Homie {
+            device_topic,
+            properties,
+            attributes,
+        }
+    }
+
+    /// Topic of the device state, the status topic of the sink.
+    pub(super) fn state_topic(&self) -> String {
+        format!("{}/$state", self.device_topic)
+    }
+
+    pub(super) fn property_topic(&self, sensor: u16) -> Option<&str> {
+        self.properties.get(&sensor).map(String::as_str)
+    }
+
+    pub(super) fn attributes(&self) -> &[(String, String)] {
+        &self.attributes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> MqttConfig {
+        let config = format!("host = \"localhost\"\nport = 1883\ntopic-base = \"arexx\"\nconvention = \"homie\"\n{}", toml);
+        toml::from_str(&config).unwrap()
+    }
+
+    fn sensors() -> Vec<SensorConfig> {
+        vec![
+            toml::from_str("id = 1111\nname = \"Cellar\"").unwrap(),
+            toml::from_str("id = 2222\nname = \"Cellar Humidity\"\nkind = \"Relative Humidity\"\nunit = \"%rH\"").unwrap(),
+        ]
+    }
+
+    fn attribute<'a>(homie: &'a Homie, topic: &str) -> Option<&'a str> {
+        homie.attributes().iter().find(|(t, _)| t == topic).map(|(_, payload)| payload.as_str())
+    }
+
+    #[test]
+    fn builds_device_and_property_topics() {
+        let homie = Homie::new(&config(""), &sensors(), "Home Cellar", |_| true);
+        assert_eq!(homie.state_topic(), "homie/arexx-home-cellar/$state");
+        assert_eq!(homie.property_topic(1111), Some("homie/arexx-home-cellar/sensor-1111/temperature"));
+        assert_eq!(homie.property_topic(2222), Some("homie/arexx-home-cellar/sensor-2222/relative-humidity"));
+        assert_eq!(homie.property_topic(3333), None);
+    }
+
+    #[test]
+    fn publishes_state_init_first_and_sensor_attributes() {
+        let homie = Homie::new(&config(""), &sensors(), "BS510", |sensor| sensor == 1111);
+        assert_eq!(homie.attributes()[0], ("homie/arexx-bs510/$state".to_string(), "init".to_string()));
+        assert_eq!(attribute(&homie, "homie/arexx-bs510/$nodes"), Some("sensor-1111,sensor-2222"));
+        assert_eq!(attribute(&homie, "homie/arexx-bs510/sensor-2222/$properties"), Some("relative-humidity"));
+        assert_eq!(attribute(&homie, "homie/arexx-bs510/sensor-1111/temperature/$unit"), Some("°C"));
+        assert_eq!(attribute(&homie, "homie/arexx-bs510/sensor-2222/relative-humidity/$unit"), Some("%rH"));
+        assert_eq!(attribute(&homie, "homie/arexx-bs510/sensor-1111/temperature/$retained"), Some("true"));
+        assert_eq!(attribute(&homie, "homie/arexx-bs510/sensor-2222/relative-humidity/$retained"), Some("false"));
+    }
+
+    #[test]
+    fn uses_configured_prefix_and_device_id() {
+        let config = config("homie-prefix = \"devices\"\nhomie-device-id = \"cellar\"");
+        let homie = Homie::new(&config, &sensors(), "BS510", |_| true);
+        assert_eq!(homie.state_topic(), "devices/cellar/$state");
+        assert_eq!(homie.property_topic(1111), Some("devices/cellar/sensor-1111/temperature"));
+    }
+}
@@ -0,0 +1,280 @@
+//! Encoding of the MQTT payload of a reading.
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::arexx::TemperatureReading;
+use crate::config::{MqttConfig, MqttPayloadFormat, SensorConfig, TimestampFormat};
+use crate::error::ConfigError;
+
+use super::MqttError;
+
+/// Field of the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Time,
+    Value,
+    Sensor,
+    Name,
+    Kind,
+    Station,
+    /// All tags of the sensor.
+    Tags,
+    Tag(String),
+}
+
+impl Field {
+    fn parse(name: &str) -> Field {
+        match name {
+            "time" => Field::Time,
+            "value" => Field::Value,
+            "sensor" => Field::Sensor,
+            "name" => Field::Name,
+            "kind" => Field::Kind,
+            "station" => Field::Station,
+            "tags" => Field::Tags,
+            tag => Field::Tag(tag.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(untagged)]
+enum FieldValue {
+    Text(String),
+    Integer(i64),
+    Float(f32),
+}
+
+impl FieldValue {
+    /// CSV column, quoted if it contains a separator, quote or line break.
+    fn to_csv(&self) -> String {
+        match self {
+            FieldValue::Text(text) if text.contains([',', '"', '\n', '\r']) => {
+                format!("\"{}\"", text.replace('"', "\"\""))
+            }
+            FieldValue::Text(text) => text.clone(),
+            FieldValue::Integer(integer) => integer.to_string(),
+            FieldValue::Float(float) => float.to_string(),
+        }
+    }
+}
+
+/// Fields of a reading in the configured order, serialized as map.
+struct Record(Vec<(String, FieldValue)>);
+
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+pub(super) struct PayloadEncoder {
+    format: MqttPayloadFormat,
+    fields: Vec<Field>,
+    timestamp_format: TimestampFormat,
+}
+
+impl PayloadEncoder {
+    pub(super) fn new(config: &MqttConfig) -> PayloadEncoder {
+        let fields = match &config.fields {
+            Some(fields) => fields.iter().map(|field| Field::parse(field)).collect(),
+            None if config.format == MqttPayloadFormat::Csv => vec![Field::Time, Field::Value],
+            None => vec![Field::Time, Field::Value, Field::Tags],
+        };
+        PayloadEncoder {
+            format: config.format,
+            fields,
+            timestamp_format: config.timestamp_format,
+        }
+    }
+
+    /// Checks the fields, every tag field must be defined for all sensors.
+    pub(super) fn validate(config: &MqttConfig, sensors: &[SensorConfig]) -> Result<(), ConfigError> {
+        let Some(fields) = &config.fields else {
+            return Ok(());
+        };
+        if config.format == MqttPayloadFormat::Plain {
+            return Err(ConfigError::Invalid("MQTT: `fields` are not supported with `format = \"plain\"`".into()));
+        }
+        if fields.is_empty() {
+            return Err(ConfigError::Invalid("MQTT: `fields` must not be empty".into()));
+        }
+        for field in fields {
+            if let Field::Tag(tag) = Field::parse(field) {
+                if let Some(sensor) = sensors.iter().find(|sensor| !sensor.tags.contains_key(&tag)) {
+                    return Err(ConfigError::Invalid(format!(
+                        "MQTT: field `{}` is no tag of sensor {} ({})",
+                        tag, sensor.id, sensor.name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn encode(&self, reading: &TemperatureReading) -> Result<Vec<u8>, MqttError> {
+        let encode_error = |error: &dyn std::fmt::Display| MqttError::Encode(error.to_string());
+        match self.format {
+            MqttPayloadFormat::Plain => Ok(reading.value.to_string().into_bytes()),
+            MqttPayloadFormat::Csv => {
+                let Record(fields) = self.record(reading);
+                let columns: Vec<String> = fields.iter().map(|(_, value)| value.to_csv()).collect();
+                Ok(columns.join(",").into_bytes())
+            }
+            MqttPayloadFormat::Json => serde_json::to_vec(&self.record(reading)).map_err(|error| encode_error(&error)),
+            MqttPayloadFormat::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(&self.record(reading), &mut payload).map_err(|error| encode_error(&error))?;
+                Ok(payload)
+            }
+            MqttPayloadFormat::MessagePack => rmp_serde::to_vec(&self.record(reading)).map_err(|error| encode_error(&error)),
+        }
+    }
+
+    /// Home Assistant template which extracts the value from the payload,
+    /// `None` if the payload is the bare value. Binary formats and payloads
+    /// without value can't be used by Home Assistant.
+    pub(super) fn value_template(&self) -> Result<Option<String>, ConfigError> {
+        let position = self.fields.iter().position(|field| *field == Field::Value);
+        match (self.format, position) {
+            (MqttPayloadFormat::Plain, _) => Ok(None),
+            (MqttPayloadFormat::Json, Some(_)) => Ok(Some("{{ value_json.value }}".into())),
+            (MqttPayloadFormat::Csv, Some(position)) if !self.fields[..position].contains(&Field::Tags) => {
+                Ok(Some(format!("{{{{ value.split(',')[{}] }}}}", position)))
+            }
+            (MqttPayloadFormat::Cbor | MqttPayloadFormat::MessagePack, _) => Err(ConfigError::Invalid(
+                "MQTT: Home Assistant discovery requires a json, plain or csv payload".into(),
+            )),
+            (_, _) => Err(ConfigError::Invalid(
+                "MQTT: Home Assistant discovery requires the `value` field at a fixed position".into(),
+            )),
+        }
+    }
+
+    fn record(&self, reading: &TemperatureReading) -> Record {
+        let mut record = Vec::new();
+        for field in &self.fields {
+            match field {
+                Field::Time => record.push(("time".into(), self.timestamp(reading))),
+                Field::Value => record.push(("value".into(), FieldValue::Float(reading.value))),
+                Field::Sensor => record.push(("sensor".into(), FieldValue::Integer(reading.sensor.into()))),
+                Field::Name => record.push(("name".into(), FieldValue::Text(reading.name.clone()))),
+                Field::Kind => record.push(("kind".into(), FieldValue::Text(reading.kind.clone()))),
+                Field::Station => record.push(("station".into(), FieldValue::Text(reading.station.clone()))),
+                Field::Tags => {
+                    for (tag, tag_value) in &reading.tags {
+                        record.push((tag.clone(), FieldValue::Text(tag_value.clone())));
+                    }
+                }
+                Field::Tag(tag) => {
+                    if let Some(tag_value) = reading.tags.get(tag) {
+                        record.push((tag.clone(), FieldValue::Text(tag_value.clone())));
+                    }
+                }
+            }
+        }
+        Record(record)
+    }
+
+    fn timestamp(&self, reading: &TemperatureReading) -> FieldValue {
+        match self.timestamp_format {
+            TimestampFormat::Rfc3339 => FieldValue::Text(reading.timestamp.to_rfc3339()),
+            TimestampFormat::EpochSeconds => FieldValue::Integer(reading.timestamp.timestamp()),
+            TimestampFormat::EpochMillis => FieldValue::Integer(reading.timestamp.timestamp_millis()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(toml: &str) -> MqttConfig {
+        toml::from_str(&format!("host = \"localhost\"\nport = 1883\ntopic-base = \"arexx\"\n{}", toml)).unwrap()
+    }
+
+    fn encoder(toml: &str) -> PayloadEncoder {
+        PayloadEncoder::new(&config(toml))
+    }
+
+    fn reading() -> TemperatureReading {
+        TemperatureReading {
+            timestamp: DateTime::parse_from_rfc3339("2024-07-01T12:00:00+02:00").unwrap(),
+            sensor: 1111,
+            name: "Cellar, north".into(),
+            kind: "temperature".into(),
+            station: "BS510".into(),
+            tags: [("room".to_string(), "cellar".to_string())].into(),
+            value: 21.5,
+            raw_value: None,
+            scaling: None,
+        }
+    }
+
+    fn encode(toml: &str) -> Vec<u8> {
+        encoder(toml).encode(&reading()).unwrap()
+    }
+
+    #[test]
+    fn encodes_json_fields_in_order() {
+        assert_eq!(encode(""), br#"{"time":"2024-07-01T12:00:00+02:00","value":21.5,"room":"cellar"}"#);
+        assert_eq!(
+            encode("fields = [\"sensor\", \"name\", \"kind\", \"station\", \"room\", \"unknown\"]"),
+            br#"{"sensor":1111,"name":"Cellar, north","kind":"temperature","station":"BS510","room":"cellar"}"#
+        );
+    }
+
+    #[test]
+    fn encodes_plain_value() {
+        assert_eq!(encode("format = \"plain\""), b"21.5");
+    }
+
+    #[test]
+    fn encodes_quoted_csv_columns() {
+        assert_eq!(encode("format = \"csv\""), b"2024-07-01T12:00:00+02:00,21.5");
+        assert_eq!(encode("format = \"csv\"\nfields = [\"name\", \"value\"]"), b"\"Cellar, north\",21.5");
+    }
+
+    #[test]
+    fn encodes_epoch_timestamps() {
+        assert_eq!(encode("fields = [\"time\"]\ntimestamp-format = \"epoch-seconds\""), br#"{"time":1719828000}"#);
+        assert_eq!(encode("fields = [\"time\"]\ntimestamp-format = \"epoch-millis\""), br#"{"time":1719828000000}"#);
+    }
+
+    #[test]
+    fn encodes_binary_maps() {
+        let expected = json!({"time": "2024-07-01T12:00:00+02:00", "value": 21.5, "room": "cellar"});
+        let cbor: serde_json::Value = ciborium::from_reader(encode("format = \"cbor\"").as_slice()).unwrap();
+        assert_eq!(cbor, expected);
+        let message_pack: serde_json::Value = rmp_serde::from_slice(&encode("format = \"msgpack\"")).unwrap();
+        assert_eq!(message_pack, expected);
+    }
+
+    #[test]
+    fn builds_value_templates() {
+        assert_eq!(encoder("").value_template().unwrap().as_deref(), Some("{{ value_json.value }}"));
+        assert_eq!(encoder("format = \"plain\"").value_template().unwrap(), None);
+        assert_eq!(encoder("format = \"csv\"").value_template().unwrap().as_deref(), Some("{{ value.split(',')[1] }}"));
+        assert!(encoder("format = \"csv\"\nfields = [\"tags\", \"value\"]").value_template().is_err());
+        assert!(encoder("format = \"cbor\"").value_template().is_err());
+    }
+
+    #[test]
+    fn rejects_tag_fields_missing_in_sensors() {
+        let sensors: Vec<SensorConfig> = vec![
+            toml::from_str("id = 1111\nname = \"Cellar\"\n[tags]\nroom = \"cellar\"").unwrap(),
+            toml::from_str("id = 2222\nname = \"Garden\"").unwrap(),
+        ];
+        assert!(PayloadEncoder::validate(&config("fields = [\"value\", \"room\"]"), &sensors[..1]).is_ok());
+        assert!(PayloadEncoder::validate(&config("fields = [\"value\", \"room\"]"), &sensors).is_err());
+        assert!(PayloadEncoder::validate(&config("fields = []"), &sensors).is_err());
+        assert!(PayloadEncoder::validate(&config("format = \"plain\"\nfields = [\"value\"]"), &sensors).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Registry of the sink types, keyed by the `type` of the `[[sink]]` entries.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::config::{ConfigFile, SinkConfig};
+use crate::error::{ConfigError, SinkError};
+use crate::spool::Spool;
+
+use super::data_file::DataFileSinkFactory;
+use super::influxdb::InfluxDbSinkFactory;
+use super::mqtt::MqttSinkFactory;
+use super::{Sink, SpooledSink};
+
+/// A sink of the configuration which could not be created.
+#[derive(Debug)]
+pub struct SinkFailure {
+    /// Position of the entry in the configuration, starting at 1.
+    pub position: usize,
+    pub sink_type: String,
+    pub name: Option<String>,
+    pub required: bool,
+    pub error: SinkError,
+}
+
+impl fmt::Display for SinkFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "sink #{} `{}` ({}): {}", self.position, name, self.sink_type, self.error),
+            None => write!(f, "sink #{} ({}): {}", self.position, self.sink_type, self.error),
+        }
+    }
+}
+
+/// Creates the sinks of one type.
+pub trait SinkFactory: Send + Sync {
+    /// The `type` of the sink entries, e.g. `InfluxDB`.
+    fn type_name(&self) -> &str;
+
+    /// Checks the settings of a sink entry when the configuration is loaded.
+    fn validate(&self, _config: &SinkConfig, _config_file: &ConfigFile) -> Result<(), ConfigError> {
+        Ok(())
+    }
+
+    fn create(&self, config: &SinkConfig, config_file: &ConfigFile) -> Result<Box<dyn Sink>, SinkError>;
+}
+
+/// The known sink types. The default registry contains the built-in sinks,
+/// further sink types are added with [`SinkRegistry::register`].
+pub struct SinkRegistry {
+    factories: BTreeMap<String, Box<dyn SinkFactory>>,
+}
+
+impl SinkRegistry {
+    /// An empty registry.
+    pub fn new() -> Self {
+        SinkRegistry {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a sink type, replacing a registered type of the same name.
+    pub fn register(&mut self, factory: impl SinkFactory + 'static) {
+        self.factories.insert(factory.type_name().to_string(), Box::new(factory));
+    }
+
+    pub fn type_names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    fn factory(&self, sink_type: &str) -> Result<&dyn SinkFactory, ConfigError> {
+        self.factories.get(sink_type).map(Box::as_ref).ok_or_else(|| {
+            ConfigError::Invalid(format!(
+                "unknown sink type `{}`, expected one of: {}",
+                sink_type,
+                self.type_names().collect::<Vec<_>>().join(", ")
+            ))
+        })
+    }
+
+    /// Checks that the type of the sink entry is known and its settings are valid.
+    pub fn validate(&self, config: &SinkConfig, config_file: &ConfigFile) -> Result<(), ConfigError> {
+        let factory = self.factory(&config.sink_type)?;
+        if config.options.timeout_ms == Some(0) {
+            return Err(ConfigError::Invalid(format!("{} sink: `timeout-ms` must be greater than 0", config.sink_type)));
+        }
+        config.options.filter.validate().map_err(|error| match error {
+            ConfigError::Invalid(message) => ConfigError::Invalid(format!("{} sink: {}", config.sink_type, message)),
+            error => error,
+        })?;
+        factory.validate(config, config_file)
+    }
+
+    pub fn create(&self, config: &SinkConfig, config_file: &ConfigFile) -> Result<Box<dyn Sink>, SinkError> {
+        self.factory(&config.sink_type)?.create(config, config_file)
+    }
+
+    /// Creates the enabled sinks of the configuration, which must have been
+    /// validated with [`ConfigFile::validate_with`]. Every sink which cannot
+    /// be created, e.g. a data file in a missing directory, is logged and
+    /// returned as failure, the caller decides whether to continue without
+    /// it.
+    pub fn assemble(&self, config_file: &ConfigFile) -> (Vec<SpooledSink>, Vec<SinkFailure>) {
+        let mut sinks: Vec<SpooledSink> = Vec::new();
+        let mut failures: Vec<SinkFailure> = Vec::new();
+        for (index, config) in config_file.sink.iter().enumerate().filter(|(_, config)| config.enabled) {
+            let position = index + 1;
+            let sink = match self.create(config, config_file) {
+                Ok(sink) => sink,
+                Err(error) => {
+                    let failure = SinkFailure {
+                        position,
+                        sink_type: config.sink_type.clone(),
+                        name: config.options.name.clone(),
+                        required: config.options.required,
+                        error,
+                    };
+                    tracing::error!("cannot create {}", failure);
+                    failures.push(failure);
+                    continue;
+                }
+            };
+            let spool = open_spool(config_file, position, config);
+            sinks.push(SpooledSink::new(
+                config.label().to_string(),
+                sink,
+                spool,
+                config.options.timeout(),
+                config.options.filter.clone(),
+            ));
+        }
+
+        (sinks, failures)
+    }
+}
+
+impl Default for SinkRegistry {
+    fn default() -> Self {
+        let mut registry = SinkRegistry::new();
+        registry.register(DataFileSinkFactory);
+        registry.register(InfluxDbSinkFactory);
+        registry.register(MqttSinkFactory);
+        registry
+    }
+}
+
+/// Opens the spool of the sink at `position` of the configuration.
+fn open_spool(config_file: &ConfigFile, position: usize, config: &SinkConfig) -> Option<Spool> {
+    let spool_config = config_file.spool.as_ref().filter(|spool_config| spool_config.enabled)?;
+    let directory = Path::new(&spool_config.directory).join(config.spool_directory(position));
+    match Spool::open(directory.clone(), spool_config) {
+        Ok(spool) => Some(spool),
+        Err(error) => {
+            tracing::error!("cannot open spool {} of {}: {}", directory.display(), config.label(), error);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_settings_of_enabled_sinks_only() {
+        let config = |enabled: bool| -> ConfigFile {
+            toml::from_str(&format!(
+                r#"
+                sensors = []
+                [[sink]]
+                type = "MQTT"
+                enabled = {}
+                host = "localhost"
+                port = 1883
+                topic-base = "arexx"
+                qos = 3
+                "#,
+                enabled
+            ))
+            .unwrap()
+        };
+        assert!(config(true).validate().is_err());
+        assert!(config(false).validate().is_ok());
+    }
+
+    #[test]
+    fn reports_every_sink_which_cannot_be_created() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+            sensors = []
+            [[sink]]
+            type = "DataFile"
+            enabled = true
+            file = "/nonexistent/arexx-tap/temperatures.jsonl"
+            [[sink]]
+            type = "InfluxDB"
+            enabled = false
+            url = 42
+            [[sink]]
+            type = "MQTT"
+            enabled = true
+            required = true
+            host = "localhost"
+            port = 1883
+            topic-base = "arexx"
+            username = "arexx"
+            password-file = "/nonexistent/arexx-tap/password"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let (sinks, failures) = SinkRegistry::default().assemble(&config);
+        assert!(sinks.is_empty());
+        let failures: Vec<(usize, &str, bool)> = failures
+            .iter()
+            .map(|failure| (failure.position, failure.sink_type.as_str(), failure.required))
+            .collect();
+        assert_eq!(failures, vec![(1, "DataFile", false), (3, "MQTT", true)]);
+    }
+}
@@ -0,0 +1,256 @@
+//! Sink worker tasks. Every sink publishes in its own task, so that a slow
+//! or stuck sink neither holds up the other sinks nor the device reader.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::arexx::TemperatureReading;
+
+use super::{SinkFilter, SinkHealth, SpooledSink};
+
+/// Number of readings waiting for a worker before new readings are dropped.
+const WORKER_QUEUE_SIZE: usize = 1024;
+/// Interval of the health and spool queue depth reports.
+const STATUS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Status of a sink worker, see [`SinkWorker::status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkStatus {
+    pub name: String,
+    pub health: SinkHealth,
+    /// Readings waiting for the worker.
+    pub pending: usize,
+    /// Readings dropped because the worker didn't keep up.
+    pub dropped: u64,
+    /// Readings in the spool of the sink.
+    pub spooled: usize,
+    /// Size of the spool on disk in bytes.
+    pub spool_size: u64,
+}
+
+/// Status of the sink, updated by the worker.
+struct SharedStatus {
+    health: SinkHealth,
+    spooled: usize,
+    spool_size: u64,
+}
+
+impl SharedStatus {
+    fn update(&mut self, sink: &SpooledSink) {
+        self.health = sink.health();
+        self.spooled = sink.queue_depth();
+        self.spool_size = sink.queue_size();
+    }
+}
+
+/// Handle of the task which publishes the readings to one sink.
+pub struct SinkWorker {
+    name: String,
+    filter: SinkFilter,
+    sender: mpsc::Sender<TemperatureReading>,
+    handle: JoinHandle<()>,
+    status: Arc<Mutex<SharedStatus>>,
+    dropped: AtomicU64,
+}
+
+impl SinkWorker {
+    pub fn spawn(sink: SpooledSink) -> SinkWorker {
+        let name = sink.to_string();
+        let filter = sink.filter().clone();
+        let status = Arc::new(Mutex::new(SharedStatus {
+            health: SinkHealth::Healthy,
+            spooled: sink.queue_depth(),
+            spool_size: sink.queue_size(),
+        }));
+        let (sender, receiver) = mpsc::channel(WORKER_QUEUE_SIZE);
+        let handle = tokio::spawn(run(sink, receiver, status.clone()));
+        SinkWorker {
+            name,
+            filter,
+            sender,
+            handle,
+            status,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Health of the sink and depth of the worker queue and the spool, as of
+    /// the last reading or replay of the worker, e.g. for monitoring.
+    pub fn status(&self) -> SinkStatus {
+        let status = self.status.lock().unwrap();
+        SinkStatus {
+            name: self.name.clone(),
+            health: status.health.clone(),
+            pending: WORKER_QUEUE_SIZE - self.sender.capacity(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            spooled: status.spooled,
+            spool_size: status.spool_size,
+        }
+    }
+
+    /// Passes a reading to the worker without waiting for the sink. Readings
+    /// which don't match the filter of the sink are skipped.
+    pub fn send(&self, reading: &TemperatureReading) {
+        if !self.filter.matches(reading) {
+            tracing::trace!("{} filtered out {}", self.name, reading);
+            return;
+        }
+        match self.sender.try_send(reading.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(reading)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("{} is not keeping up, dropped {}", self.name, reading)
+            }
+            Err(TrySendError::Closed(reading)) => {
+                tracing::error!("{} worker stopped, dropped {}", self.name, reading)
+            }
+        }
+    }
+
+    /// Publishes the pending readings, flushes the sink and waits for the
+    /// worker to stop.
+    pub async fn shutdown(self) {
+        drop(self.sender);
+        if let Err(error) = self.handle.await {
+            tracing::error!("{} worker failed: {}", self.name, error);
+        }
+    }
+}
+
+async fn run(mut sink: SpooledSink, mut receiver: mpsc::Receiver<TemperatureReading>, status: Arc<Mutex<SharedStatus>>) {
+    if let Err(error) = sink.start().await {
+        tracing::error!("error starting {}: {}", sink, error);
+    }
+
+    let mut status_report = tokio::time::interval(STATUS_REPORT_INTERVAL);
+    loop {
+        let next_replay = sink.next_replay();
+        tokio::select! {
+            reading = receiver.recv() => match reading {
+                Some(reading) => match sink.publish(&reading).await {
+                    Ok(_) => tracing::trace!("published {} to {}", &reading, sink),
+                    Err(error) if error.is_retryable() => tracing::warn!("error publishing {} to {}: {}", &reading, sink, error),
+                    Err(error) => tracing::error!("fatal error publishing {} to {}: {}", &reading, sink, error),
+                },
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next_replay.unwrap_or_else(Instant::now)), if next_replay.is_some() => {
+                if let Err(error) = sink.replay().await {
+                    tracing::warn!("error replaying spool of {}: {}", sink, error);
+                }
+            }
+            _ = status_report.tick() => report_status(&sink),
+        }
+        status.lock().unwrap().update(&sink);
+    }
+
+    if let Err(error) = sink.flush().await {
+        tracing::error!("error flushing {}: {}", sink, error);
+    }
+    if let Err(error) = sink.shutdown().await {
+        tracing::error!("error shutting down {}: {}", sink, error);
+    }
+    status.lock().unwrap().update(&sink);
+    report_status(&sink);
+}
+
+/// Logs an unhealthy sink and the number of spooled readings.
+fn report_status(sink: &SpooledSink) {
+    if let SinkHealth::Unhealthy(reason) = sink.health() {
+        tracing::warn!("{} unhealthy: {}", sink, reason);
+    }
+    if sink.queue_depth() > 0 {
+        tracing::info!("{}: {} spooled readings ({} bytes)", sink, sink.queue_depth(), sink.queue_size());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use chrono::Utc;
+
+    use super::*;
+    use crate::config::SpoolConfig;
+    use crate::error::SinkError;
+    use crate::sink::Sink;
+    use crate::spool::Spool;
+
+    /// Sink whose server is unreachable.
+    struct UnavailableSink;
+
+    impl std::fmt::Display for UnavailableSink {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "UnavailableSink")
+        }
+    }
+
+    #[async_trait]
+    impl Sink for UnavailableSink {
+        async fn publish(&self, _reading: &TemperatureReading) -> Result<(), SinkError> {
+            Err(SinkError::Other {
+                source: "unreachable".into(),
+                retryable: true,
+            })
+        }
+
+        fn health(&self) -> SinkHealth {
+            SinkHealth::Unhealthy("unreachable".into())
+        }
+    }
+
+    fn reading(value: f32) -> TemperatureReading {
+        TemperatureReading {
+            timestamp: Utc::now().fixed_offset(),
+            sensor: 1111,
+            name: "Kitchen".into(),
+            kind: "temperature".into(),
+            station: "BS510".into(),
+            tags: Default::default(),
+            value,
+            raw_value: None,
+            scaling: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_health_and_spooled_readings() {
+        let directory = std::env::temp_dir().join(format!("arexx-tap-{}-worker-status", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let config = SpoolConfig {
+            enabled: true,
+            directory: String::new(),
+            max_size_mb: None,
+            max_age_hours: None,
+        };
+        let spool = Spool::open(directory.clone(), &config).unwrap();
+        let timeout = Duration::from_secs(1);
+        let sink = SpooledSink::new("unavailable".into(), Box::new(UnavailableSink), Some(spool), timeout, SinkFilter::default());
+        let worker = SinkWorker::spawn(sink);
+        assert_eq!(worker.status().spooled, 0);
+
+        worker.send(&reading(1.0));
+        worker.send(&reading(2.0));
+        let status = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let status = worker.status();
+                if status.spooled == 2 {
+                    break status;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status.health, SinkHealth::Unhealthy("unreachable".into()));
+        assert_eq!((status.pending, status.dropped), (0, 0));
+        assert!(status.spool_size > 0);
+
+        worker.shutdown().await;
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
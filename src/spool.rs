@@ -0,0 +1,414 @@
+//! Persistent store-and-forward queue of readings.
+//!
+//! Readings which could not be published are appended to a segment log in
+//! the spool directory of the sink and replayed in order once the sink
+//! recovers. A segment is a JSON lines file (`<sequence>.jsonl`), the
+//! position of the next reading to replay is kept in the `cursor` file.
+//! Replayed readings are only removed once they are committed, i.e. the
+//! sink wrote them, so a crash replays them again rather than losing them.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::arexx::TemperatureReading;
+use crate::config::SpoolConfig;
+
+const SEGMENT_EXTENSION: &str = "jsonl";
+const CURSOR_FILE: &str = "cursor";
+const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cursor {
+    segment: u64,
+    offset: u64,
+}
+
+/// Read position of the spool, see [`Spool::position`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpoolPosition {
+    cursor: Cursor,
+    /// Readings between the committed cursor and the position.
+    count: usize,
+}
+
+/// On-disk queue of the readings of one sink.
+#[derive(Debug)]
+pub struct Spool {
+    directory: PathBuf,
+    max_size: u64,
+    max_age: Option<Duration>,
+    segment_size: u64,
+    /// Sequence numbers of the segments, oldest first.
+    segments: Vec<u64>,
+    /// Position of the oldest reading which is not committed.
+    cursor: Cursor,
+    /// Number of queued readings after the cursor.
+    depth: usize,
+    /// Position of the next reading to read.
+    read: SpoolPosition,
+}
+
+fn segment_path(directory: &Path, segment: u64) -> PathBuf {
+    directory.join(format!("{:010}.{}", segment, SEGMENT_EXTENSION))
+}
+
+/// Counts the lines of a segment starting at `offset`.
+fn count_lines(path: &Path, offset: u64) -> std::io::Result<usize> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(BufReader::new(file).lines().count())
+}
+
+impl Spool {
+    /// Opens the queue in `directory`, continuing with spooled readings of a
+    /// previous run.
+    pub fn open(directory: PathBuf, config: &SpoolConfig) -> std::io::Result<Spool> {
+        fs::create_dir_all(&directory)?;
+
+        let mut segments: Vec<u64> = fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse::<u64>().ok())
+            .collect();
+        segments.sort_unstable();
+
+        let cursor = fs::read_to_string(directory.join(CURSOR_FILE))
+            .ok()
+            .and_then(|content| {
+                let (segment, offset) = content.trim().split_once(' ')?;
+                Some(Cursor {
+                    segment: segment.parse().ok()?,
+                    offset: offset.parse().ok()?,
+                })
+            })
+            .filter(|cursor| segments.contains(&cursor.segment))
+            .unwrap_or(Cursor {
+                segment: segments.first().copied().unwrap_or_default(),
+                offset: 0,
+            });
+
+        let mut depth = 0;
+        for segment in segments.iter().filter(|segment| **segment >= cursor.segment) {
+            let offset = if *segment == cursor.segment { cursor.offset } else { 0 };
+            depth += count_lines(&segment_path(&directory, *segment), offset)?;
+        }
+
+        let max_size = config.max_size_mb() * 1024 * 1024;
+        let spool = Spool {
+            directory,
+            max_size,
+            max_age: config.max_age_hours.map(|hours| Duration::from_secs(hours * 3600)),
+            segment_size: DEFAULT_SEGMENT_SIZE.min(max_size / 4).max(1),
+            segments,
+            cursor,
+            depth,
+            read: SpoolPosition { cursor, count: 0 },
+        };
+        if depth > 0 {
+            tracing::info!("spool {}: {} queued readings", spool.directory.display(), depth);
+        }
+        Ok(spool)
+    }
+
+    /// Number of queued readings.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depth == 0
+    }
+
+    /// Size of the queue on disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.segments
+            .iter()
+            .filter_map(|segment| fs::metadata(segment_path(&self.directory, *segment)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// Appends a reading to the queue.
+    pub fn push(&mut self, reading: &TemperatureReading) -> std::io::Result<()> {
+        let line = serde_json::to_string(reading)?;
+
+        let segment = match self.segments.last() {
+            Some(segment)
+                if fs::metadata(segment_path(&self.directory, *segment)).map_or(0, |metadata| metadata.len())
+                    < self.segment_size =>
+            {
+                *segment
+            }
+            Some(segment) => segment + 1,
+            None => self.cursor.segment,
+        };
+        if self.segments.last() != Some(&segment) {
+            self.segments.push(segment);
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.directory, segment))?;
+        writeln!(file, "{}", line)?;
+        self.depth += 1;
+
+        self.enforce_max_size()
+    }
+
+    /// Drops the oldest segments while the queue exceeds its maximum size.
+    fn enforce_max_size(&mut self) -> std::io::Result<()> {
+        while self.segments.len() > 1 && self.size() > self.max_size {
+            let oldest = self.segments[0];
+            let offset = if oldest == self.cursor.segment { self.cursor.offset } else { 0 };
+            let dropped = count_lines(&segment_path(&self.directory, oldest), offset)?;
+            tracing::warn!("spool {}: maximum size exceeded, dropped {} readings", self.directory.display(), dropped);
+            self.remove_oldest_segment()?;
+            self.depth -= dropped;
+        }
+        Ok(())
+    }
+
+    fn remove_oldest_segment(&mut self) -> std::io::Result<()> {
+        let oldest = self.segments.remove(0);
+        fs::remove_file(segment_path(&self.directory, oldest))?;
+        self.cursor = Cursor {
+            segment: self.segments.first().copied().unwrap_or(oldest + 1),
+            offset: 0,
+        };
+        self.rewind();
+        self.write_cursor()
+    }
+
+    fn write_cursor(&self) -> std::io::Result<()> {
+        fs::write(
+            self.directory.join(CURSOR_FILE),
+            format!("{} {}", self.cursor.segment, self.cursor.offset),
+        )
+    }
+
+    /// Reads the next reading to replay and advances the read position. The
+    /// reading stays queued until it is committed. Readings older than the
+    /// maximum age and unreadable lines are skipped.
+    pub fn next_reading(&mut self) -> std::io::Result<Option<TemperatureReading>> {
+        while self.depth > self.read.count {
+            let mut file = File::open(segment_path(&self.directory, self.read.cursor.segment))?;
+            file.seek(SeekFrom::Start(self.read.cursor.offset))?;
+            let mut line = String::new();
+            let len = BufReader::new(file).read_line(&mut line)?;
+            if len == 0 {
+                // segment completely read, continue with the next one
+                match self.segments.iter().find(|segment| **segment > self.read.cursor.segment) {
+                    Some(segment) => {
+                        self.read.cursor = Cursor {
+                            segment: *segment,
+                            offset: 0,
+                        }
+                    }
+                    None => break,
+                }
+                continue;
+            }
+            self.read.cursor.offset += len as u64;
+            self.read.count += 1;
+
+            match serde_json::from_str::<TemperatureReading>(&line) {
+                Ok(reading) if self.is_expired(&reading) => {
+                    tracing::debug!("spool {}: dropped expired {}", self.directory.display(), reading);
+                }
+                Ok(reading) => return Ok(Some(reading)),
+                Err(error) => {
+                    tracing::warn!("spool {}: skipped unreadable reading: {}", self.directory.display(), error);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read position after the last reading returned by
+    /// [`Spool::next_reading`].
+    pub fn position(&self) -> SpoolPosition {
+        self.read
+    }
+
+    /// Removes the readings up to `position` from the queue. The position
+    /// must have been taken after the last commit or rewind.
+    pub fn commit(&mut self, position: SpoolPosition) -> std::io::Result<()> {
+        self.cursor = position.cursor;
+        self.depth -= position.count;
+        self.read.count -= position.count;
+        if self.depth == 0 {
+            // everything replayed, start over with empty segments
+            for segment in std::mem::take(&mut self.segments) {
+                fs::remove_file(segment_path(&self.directory, segment))?;
+            }
+            self.cursor = Cursor {
+                segment: self.cursor.segment + 1,
+                offset: 0,
+            };
+            self.rewind();
+        } else {
+            while self.segments.first().is_some_and(|segment| *segment < self.cursor.segment) {
+                let segment = self.segments.remove(0);
+                fs::remove_file(segment_path(&self.directory, segment))?;
+            }
+        }
+        self.write_cursor()
+    }
+
+    /// Resets the read position to the oldest reading which is not committed.
+    pub fn rewind(&mut self) {
+        self.read = SpoolPosition {
+            cursor: self.cursor,
+            count: 0,
+        };
+    }
+
+    fn is_expired(&self, reading: &TemperatureReading) -> bool {
+        match self.max_age {
+            Some(max_age) => Utc::now()
+                .signed_duration_since(reading.timestamp)
+                .to_std()
+                .is_ok_and(|age| age > max_age),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("arexx-tap-spool-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn config(max_age_hours: Option<u64>) -> SpoolConfig {
+        SpoolConfig {
+            enabled: true,
+            directory: String::new(),
+            max_size_mb: None,
+            max_age_hours,
+        }
+    }
+
+    fn reading(value: f32) -> TemperatureReading {
+        TemperatureReading {
+            timestamp: Utc::now().fixed_offset(),
+            sensor: 1111,
+            name: "Kitchen".into(),
+            kind: "temperature".into(),
+            station: "BS510".into(),
+            tags: Default::default(),
+            value,
+            raw_value: Some(2750),
+            scaling: Some(0.0078),
+        }
+    }
+
+    fn read_values(spool: &mut Spool) -> Vec<f32> {
+        std::iter::from_fn(|| spool.next_reading().unwrap()).map(|reading| reading.value).collect()
+    }
+
+    #[test]
+    fn continues_after_committed_cursor_on_reopen() {
+        let directory = directory("reopen");
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        for value in [1.0, 2.0, 3.0] {
+            spool.push(&reading(value)).unwrap();
+        }
+        assert_eq!(spool.next_reading().unwrap().unwrap().value, 1.0);
+        spool.commit(spool.position()).unwrap();
+        // read, but not committed
+        assert_eq!(spool.next_reading().unwrap().unwrap().value, 2.0);
+        drop(spool);
+
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        assert_eq!(spool.depth(), 2);
+        assert_eq!(read_values(&mut spool), vec![2.0, 3.0]);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn reads_and_commits_across_segments() {
+        let directory = directory("segments");
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        // every reading starts a new segment
+        spool.segment_size = 1;
+        for value in [1.0, 2.0, 3.0, 4.0] {
+            spool.push(&reading(value)).unwrap();
+        }
+        assert_eq!(spool.segments.len(), 4);
+
+        spool.next_reading().unwrap();
+        spool.next_reading().unwrap();
+        let position = spool.position();
+        assert_eq!(spool.next_reading().unwrap().unwrap().value, 3.0);
+        spool.commit(position).unwrap();
+        assert_eq!(spool.segments.len(), 3);
+        spool.rewind();
+        assert_eq!(read_values(&mut spool), vec![3.0, 4.0]);
+        drop(spool);
+
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        assert_eq!(spool.depth(), 2);
+        assert_eq!(read_values(&mut spool), vec![3.0, 4.0]);
+        spool.commit(spool.position()).unwrap();
+        assert!(spool.is_empty());
+        assert!(spool.segments.is_empty());
+        drop(spool);
+
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        assert!(spool.is_empty());
+        spool.push(&reading(5.0)).unwrap();
+        assert_eq!(read_values(&mut spool), vec![5.0]);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn starts_at_oldest_segment_with_invalid_cursor() {
+        let directory = directory("invalid-cursor");
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        spool.push(&reading(1.0)).unwrap();
+        spool.push(&reading(2.0)).unwrap();
+        drop(spool);
+
+        fs::write(directory.join(CURSOR_FILE), "garbage").unwrap();
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        assert_eq!(read_values(&mut spool), vec![1.0, 2.0]);
+        drop(spool);
+
+        // cursor of a segment which no longer exists
+        fs::write(directory.join(CURSOR_FILE), "42 17").unwrap();
+        let mut spool = Spool::open(directory.clone(), &config(None)).unwrap();
+        assert_eq!(read_values(&mut spool), vec![1.0, 2.0]);
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn skips_expired_and_unreadable_readings() {
+        let directory = directory("skip");
+        let mut spool = Spool::open(directory.clone(), &config(Some(1))).unwrap();
+        let mut expired = reading(1.0);
+        expired.timestamp = (Utc::now() - chrono::Duration::hours(2)).fixed_offset();
+        spool.push(&expired).unwrap();
+        spool.push(&reading(2.0)).unwrap();
+        let segment = segment_path(&directory, spool.segments[0]);
+        let mut file = OpenOptions::new().append(true).open(segment).unwrap();
+        writeln!(file, "not json").unwrap();
+        spool.depth += 1;
+        spool.push(&reading(3.0)).unwrap();
+
+        assert_eq!(read_values(&mut spool), vec![2.0, 3.0]);
+        spool.commit(spool.position()).unwrap();
+        assert!(spool.is_empty());
+        let _ = fs::remove_dir_all(directory);
+    }
+}
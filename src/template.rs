@@ -0,0 +1,260 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::arexx::TemperatureReading;
+use crate::config::{SensorConfig, DEFAULT_SENSOR_KIND};
+use crate::error::ConfigError;
+use crate::sink::slugify;
+
+/// Placeholders which are available for every sensor. Any other placeholder
+/// refers to a tag of the sensor.
+pub const BUILTIN_PLACEHOLDERS: [&str; 4] = ["id", "name", "kind", "station"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// Name template with sensor placeholders, e.g. `home/{room}/{name}/temperature`.
+///
+/// Placeholders are `{id}`, `{name}`, `{kind}`, `{station}` and the keys of
+/// the sensor tags. Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, ConfigError> {
+        let invalid = |reason: &str| ConfigError::Invalid(format!("template `{}`: {}", source, reason));
+
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => return Err(invalid("unclosed placeholder")),
+                            Some(c) => placeholder.push(c),
+                        }
+                    }
+                    let placeholder = placeholder.trim();
+                    if placeholder.is_empty() {
+                        return Err(invalid("empty placeholder"));
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder.to_string()));
+                }
+                '}' => return Err(invalid("unmatched `}`")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template {
+            source: source.to_string(),
+            segments,
+        })
+    }
+
+    /// Placeholders of the template which are not built-in, i.e. sensor tags.
+    pub fn tag_placeholders(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Placeholder(name) if !BUILTIN_PLACEHOLDERS.contains(&name.as_str()) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Checks that every tag placeholder is defined for all sensors, so that
+    /// rendering never misses a value.
+    pub fn validate(&self, sensors: &[SensorConfig]) -> Result<(), ConfigError> {
+        for tag in self.tag_placeholders() {
+            if let Some(sensor) = sensors.iter().find(|sensor| !sensor.tags.contains_key(tag)) {
+                return Err(ConfigError::Invalid(format!(
+                    "template `{}`: sensor {} ({}) has no tag `{}`",
+                    self.source, sensor.id, sensor.name, tag
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a topic template in addition to [`Template::validate`]: the
+    /// rendered topic of every sensor must not contain the wildcards `+` and
+    /// `#` or an empty level.
+    pub fn validate_topic(&self, sensors: &[SensorConfig], station: &str) -> Result<(), ConfigError> {
+        self.validate(sensors)?;
+        for sensor in sensors {
+            let topic = self.render_sensor(sensor, station, slugify);
+            check_topic(&topic).map_err(|reason| {
+                ConfigError::Invalid(format!(
+                    "template `{}`: topic `{}` of sensor {} ({}) {}",
+                    self.source, topic, sensor.id, sensor.name, reason
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Renders the template for a reading. The substituted values are passed
+    /// through `escape`, e.g. to turn them into valid MQTT topic levels.
+    pub fn render(&self, reading: &TemperatureReading, escape: impl Fn(&str) -> String) -> String {
+        self.render_with(
+            |name| match name {
+                "id" => reading.sensor.to_string(),
+                "name" => reading.name.clone(),
+                "kind" => reading.kind.clone(),
+                "station" => reading.station.clone(),
+                tag => reading.tags.get(tag).cloned().unwrap_or_default(),
+            },
+            escape,
+        )
+    }
+
+    /// Renders the template for a configured sensor of `station`, e.g. to
+    /// announce the topic before the first reading.
+    pub fn render_sensor(&self, sensor: &SensorConfig, station: &str, escape: impl Fn(&str) -> String) -> String {
+        self.render_with(
+            |name| match name {
+                "id" => sensor.id.to_string(),
+                "name" => sensor.name.clone(),
+                "kind" => sensor.kind.clone().unwrap_or(DEFAULT_SENSOR_KIND.to_string()),
+                "station" => station.to_string(),
+                tag => sensor.tags.get(tag).cloned().unwrap_or_default(),
+            },
+            escape,
+        )
+    }
+
+    fn render_with(&self, value: impl Fn(&str) -> String, escape: impl Fn(&str) -> String) -> String {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => rendered.push_str(literal),
+                Segment::Placeholder(name) => rendered.push_str(&escape(&value(name))),
+            }
+        }
+        rendered
+    }
+}
+
+/// Checks that a topic can be published to: no wildcards and no empty
+/// levels. Returns the reason otherwise.
+pub fn check_topic(topic: &str) -> Result<(), &'static str> {
+    if topic.contains(['+', '#']) {
+        Err("contains a wildcard `+` or `#`")
+    } else if topic.split('/').any(str::is_empty) {
+        Err("contains an empty level")
+    } else {
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = ConfigError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        Template::parse(&source)
+    }
+}
+
+impl From<Template> for String {
+    fn from(template: Template) -> Self {
+        template.source
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(id: u16, name: &str, tags: &[(&str, &str)]) -> SensorConfig {
+        let tags: Vec<String> = tags.iter().map(|(tag, value)| format!("{} = \"{}\"\n", tag, value)).collect();
+        toml::from_str(&format!("id = {}\nname = \"{}\"\n[tags]\n{}", id, name, tags.concat())).unwrap()
+    }
+
+    #[test]
+    fn parses_literals_and_placeholders() {
+        let template = Template::parse("home/{ room }/{name}").unwrap();
+        assert_eq!(
+            template.segments,
+            vec![
+                Segment::Literal("home/".into()),
+                Segment::Placeholder("room".into()),
+                Segment::Literal("/".into()),
+                Segment::Placeholder("name".into()),
+            ]
+        );
+        assert_eq!(template.tag_placeholders().collect::<Vec<_>>(), vec!["room"]);
+    }
+
+    #[test]
+    fn parses_escaped_braces() {
+        let template = Template::parse("{{literal}}/{id}").unwrap();
+        assert_eq!(
+            template.segments,
+            vec![Segment::Literal("{literal}/".into()), Segment::Placeholder("id".into())]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_placeholders() {
+        for source in ["home/{room", "home/{}", "home/{ro{om}", "home/room}"] {
+            assert!(Template::parse(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn renders_sensor_with_escaped_values() {
+        let template = Template::parse("{station}/{room}/{name}-{id}").unwrap();
+        let sensor = sensor(1111, "Living Room", &[("room", "Ground floor")]);
+        assert_eq!(
+            template.render_sensor(&sensor, "BS510", slugify),
+            "bs510/ground-floor/living-room-1111"
+        );
+    }
+
+    #[test]
+    fn rejects_undefined_tags() {
+        let template = Template::parse("home/{room}/{name}").unwrap();
+        let sensors = [sensor(1, "Kitchen", &[("room", "ground")]), sensor(2, "Attic", &[])];
+        assert!(template.validate(&sensors[..1]).is_ok());
+        assert!(template.validate(&sensors).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_topics() {
+        let sensors = [sensor(1, "Kitchen", &[("room", "--")])];
+        assert!(Template::parse("home/{name}").unwrap().validate_topic(&sensors, "BS510").is_ok());
+        assert!(Template::parse("home/+/{name}").unwrap().validate_topic(&sensors, "BS510").is_err());
+        assert!(Template::parse("home/{name}/#").unwrap().validate_topic(&sensors, "BS510").is_err());
+        assert!(Template::parse("home//{name}").unwrap().validate_topic(&sensors, "BS510").is_err());
+        assert!(Template::parse("home/{room}/{name}").unwrap().validate_topic(&sensors, "BS510").is_err());
+    }
+}
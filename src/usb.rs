@@ -1,13 +1,18 @@
 use std::{
-    cell::RefCell, sync::{Arc, Mutex}
+    cell::RefCell, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration
 };
 
-use anyhow::Result;
 use rusb::{
     Device, DeviceDescriptor, DeviceHandle, Direction, GlobalContext, Hotplug, TransferType, UsbContext
 };
 use tokio::task::JoinHandle;
 
+use crate::error::DeviceError;
+use crate::profile::DeviceProfile;
+
+/// Interval in which the hotplug listener checks whether it was stopped.
+const LISTENER_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
 #[derive(Debug, Clone, Copy)]
 pub struct Endpoints {
     pub config: u8,
@@ -23,29 +28,58 @@ pub struct UsbInner {
     pub handle: RefCell<DeviceHandle<GlobalContext>>,
 }
 
+impl UsbInner {
+    /// Writes a raw packet to the bulk OUT endpoint.
+    pub fn write_packet(&self, packet: &[u8], timeout: Duration) -> Result<usize, DeviceError> {
+        Ok(self.handle.borrow().write_bulk(self.endpoints.write_addr, packet, timeout)?)
+    }
+
+    /// Reads a raw packet from the bulk IN endpoint.
+    pub fn read_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceError> {
+        Ok(self.handle.borrow().read_bulk(self.endpoints.read_addr, buf, timeout)?)
+    }
+}
+
 #[derive(Debug)]
-pub(crate) struct UsbDevice {
+pub struct UsbDevice {
     pub connect_count: usize,
     pub inner: Option<UsbInner>,
     listener: Option<JoinHandle<()>>,
+    stopping: Arc<AtomicBool>,
 }
 
 impl UsbDevice {
-    pub fn new(vid: u16, pid: u16) -> Result<Arc<Mutex<UsbDevice>>> {
+    pub fn new(profile: &DeviceProfile) -> Result<Arc<Mutex<UsbDevice>>, DeviceError> {
+        let stopping = Arc::new(AtomicBool::new(false));
         let usb: Arc<Mutex<UsbDevice>> = Arc::new(Mutex::new(UsbDevice {
             connect_count: 0,
             inner: None,
             listener: None,
+            stopping: stopping.clone(),
         }));
-        usb.lock().unwrap().listener = Some(start_usb_listener(vid, pid, usb.clone()));
+        usb.lock().unwrap().listener = Some(start_usb_listener(profile, usb.clone(), stopping)?);
         Ok(usb)
     }
+
+    /// Stops the hotplug listener. The listener task keeps the device alive
+    /// and blocks the shutdown of the runtime until it is stopped.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Expected endpoint addresses, any endpoint of the transfer type matches when not set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EndpointExpectation {
+    pub read_addr: Option<u8>,
+    pub write_addr: Option<u8>,
 }
 
 fn find_endpoints<T>(
     device: &Device<T>,
     device_desc: &DeviceDescriptor,
-    transfer_type: TransferType
+    transfer_type: TransferType,
+    expected: EndpointExpectation,
 ) -> Option<Endpoints>
 where
     T: UsbContext,
@@ -58,8 +92,10 @@ where
 
         for interface in config_desc.interfaces() {
             for interface_desc in interface.descriptors() {
-                let endpoint_desc_in = interface_desc.endpoint_descriptors().find(|d| d.direction() == Direction::In && d.transfer_type() == transfer_type);
-                let endpoint_desc_out = interface_desc.endpoint_descriptors().find(|d| d.direction() == Direction::Out && d.transfer_type() == transfer_type);
+                let endpoint_desc_in = interface_desc.endpoint_descriptors().find(|d| d.direction() == Direction::In && d.transfer_type() == transfer_type
+                    && expected.read_addr.is_none_or(|addr| addr == d.address()));
+                let endpoint_desc_out = interface_desc.endpoint_descriptors().find(|d| d.direction() == Direction::Out && d.transfer_type() == transfer_type
+                    && expected.write_addr.is_none_or(|addr| addr == d.address()));
 
                 if let (Some(epd_in),Some(epd_out)) = (endpoint_desc_in, endpoint_desc_out) {
                     return Some(Endpoints {
@@ -69,8 +105,6 @@ where
                         read_addr: epd_in.address(),
                         write_addr: epd_out.address(),
                     })
-                } else {
-                    return None
                 }
             }
         }
@@ -79,7 +113,7 @@ where
     None
 }
 
-fn configure_endpoints<T: UsbContext>(handle: &mut DeviceHandle<T>, endpoints: &Endpoints) -> Result<()> {
+fn configure_endpoints<T: UsbContext>(handle: &mut DeviceHandle<T>, endpoints: &Endpoints) -> rusb::Result<()> {
     handle.set_active_configuration(endpoints.config)?;
     handle.claim_interface(endpoints.iface)?;
     handle.set_alternate_setting(endpoints.iface, endpoints.setting)?;
@@ -87,38 +121,26 @@ fn configure_endpoints<T: UsbContext>(handle: &mut DeviceHandle<T>, endpoints: &
 }
 
 // Hotplug listener
-pub(crate) struct UsbHotplugHandler {
+pub struct UsbHotplugHandler {
     usb: Arc<Mutex<UsbDevice>>,
+    expected_endpoints: EndpointExpectation,
 }
 
 impl Hotplug<GlobalContext> for UsbHotplugHandler {
     fn device_arrived(&mut self, device: Device<GlobalContext>) {
         tracing::debug!("arexx device arrived: {:?}", device);
-
-        let desc = device.device_descriptor().expect("cannot read device descriptor");
-        let mut handle = device.open().expect("cannot open device");
-
-        let endpoints = find_endpoints(&device, &desc, TransferType::Bulk).expect("could not find r/w endpoints for bulk transfer type");
-        
-        match handle.kernel_driver_active(endpoints.iface) {
-            Ok(true) => {
-                handle.detach_kernel_driver(endpoints.iface).expect("cannot detach kernel driver");
-                true
+        match open_device(&device, self.expected_endpoints) {
+            Ok((handle, endpoints)) => {
+                let mut usb = self.usb.lock().unwrap();
+                tracing::trace!("found arexx endpoints: {:?}", endpoints);
+                usb.connect_count += 1;
+                usb.inner = Some(UsbInner {
+                    endpoints,
+                    handle: RefCell::new(handle),
+                })
             }
-            _ => false,
-        };
-
-        configure_endpoints(&mut handle, &endpoints).expect("cannot configure endpoints");
-
-        tracing::trace!("endpoints = {:?}", endpoints);
-
-        let mut usb = self.usb.lock().unwrap();
-        tracing::trace!("found arexx endpoints: {:?}", endpoints);
-        usb.connect_count += 1;
-        usb.inner = Some(UsbInner {
-            endpoints,
-            handle: RefCell::new(handle),
-        })
+            Err(error) => tracing::error!("cannot open arexx device {:?}: {}", device, error),
+        }
     }
 
     fn device_left(&mut self, device: Device<GlobalContext>) {
@@ -128,10 +150,13 @@ impl Hotplug<GlobalContext> for UsbHotplugHandler {
         {
             if let Some(inner) = self.usb.lock().unwrap().inner.as_ref() {
                 let handle = inner.handle.borrow_mut();
-                handle.release_interface(inner.endpoints.iface).expect("cannot release interface");
-                match handle.kernel_driver_active(inner.endpoints.iface) {
-                    Ok(true) => handle.attach_kernel_driver(inner.endpoints.iface).expect("cannot attach kernel driver"),
-                    _ => ()
+                if let Err(error) = handle.release_interface(inner.endpoints.iface) {
+                    tracing::warn!("cannot release interface: {}", error);
+                }
+                if let Ok(true) = handle.kernel_driver_active(inner.endpoints.iface) {
+                    if let Err(error) = handle.attach_kernel_driver(inner.endpoints.iface) {
+                        tracing::warn!("cannot attach kernel driver: {}", error);
+                    }
                 }
             }
         }
@@ -140,26 +165,51 @@ impl Hotplug<GlobalContext> for UsbHotplugHandler {
     }
 }
 
-fn start_usb_listener(vid: u16, pid: u16, usb: Arc<Mutex<UsbDevice>>) -> JoinHandle<()> {
+/// Opens an arrived device and claims the interface of its bulk endpoints.
+fn open_device(
+    device: &Device<GlobalContext>,
+    expected_endpoints: EndpointExpectation,
+) -> Result<(DeviceHandle<GlobalContext>, Endpoints), DeviceError> {
+    let desc = device.device_descriptor()?;
+    let mut handle = device.open()?;
+
+    let endpoints = find_endpoints(device, &desc, TransferType::Bulk, expected_endpoints).ok_or(DeviceError::NoEndpoints)?;
+
+    if let Ok(true) = handle.kernel_driver_active(endpoints.iface) {
+        handle.detach_kernel_driver(endpoints.iface)?;
+    }
+    configure_endpoints(&mut handle, &endpoints)?;
+    tracing::trace!("endpoints = {:?}", endpoints);
+    Ok((handle, endpoints))
+}
+
+fn start_usb_listener(
+    profile: &DeviceProfile,
+    usb: Arc<Mutex<UsbDevice>>,
+    stopping: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, DeviceError> {
     let context = GlobalContext::default();
 
-    let usb_handler = Box::new(UsbHotplugHandler { usb });
-    let reg: Result<rusb::Registration<GlobalContext>, rusb::Error> = rusb::HotplugBuilder::new()
-        .vendor_id(vid)
-        .product_id(pid)
+    let expected_endpoints = EndpointExpectation {
+        read_addr: profile.read_endpoint,
+        write_addr: profile.write_endpoint,
+    };
+    let usb_handler = Box::new(UsbHotplugHandler { usb, expected_endpoints });
+    let reg: rusb::Registration<GlobalContext> = rusb::HotplugBuilder::new()
+        .vendor_id(profile.vid)
+        .product_id(profile.pid)
         .enumerate(true)
-        .register(context, usb_handler);
-
-    tokio::task::spawn_blocking(move || {
-        let _reg = Some(reg.unwrap());
-        loop {
-            match context.handle_events(None) {
-                Ok(_reg) => {}
-                Err(e) => {
-                    tracing::error!("error handling USB events: {:?}", e);
-                    break;
-                }
+        .register(context, usb_handler)
+        .map_err(DeviceError::Hotplug)?;
+
+    Ok(tokio::task::spawn_blocking(move || {
+        let _reg = Some(reg);
+        while !stopping.load(Ordering::Relaxed) {
+            if let Err(e) = context.handle_events(Some(LISTENER_POLL_INTERVAL)) {
+                tracing::error!("error handling USB events: {:?}", e);
+                break;
             }
         }
-    })
-}
\ No newline at end of file
+        tracing::debug!("USB listener stopped");
+    }))
+}